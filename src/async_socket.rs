@@ -0,0 +1,58 @@
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+// The file descriptor ZeroMQ signals a socket's events on. It
+// stays owned by the socket.
+struct EventFd(RawFd);
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+// A ZeroMQ socket that can be waited on by the runtime instead
+// of blocking a thread. Only one task may use it.
+pub(crate) struct AsyncSocket {
+    // Declared first so it's dropped, and deregistered, before
+    // the socket closes the descriptor
+    events: AsyncFd<EventFd>,
+    socket: zmq::Socket,
+}
+
+impl AsyncSocket {
+    // Has to be called from within the runtime
+    pub(crate) fn new(socket: zmq::Socket) -> io::Result<AsyncSocket> {
+        let fd = socket.get_fd()?;
+        // SAFETY: the descriptor belongs to the ZeroMQ socket,
+        // which is dropped after the AsyncFd
+        let events = unsafe { AsyncFd::register_with_interest(EventFd(fd), Interest::READABLE) }
+            .map_err(|e| e.into_parts().1)?;
+        Ok(AsyncSocket { events, socket })
+    }
+
+    // The descriptor only tells that the socket's state may have
+    // changed, so receiving is always tried before waiting. It's
+    // only signaled again after a call on the socket returned
+    // EAGAIN.
+    pub(crate) async fn recv(&self) -> zmq::Result<zmq::Message> {
+        loop {
+            match self.socket.recv_msg(zmq::DONTWAIT) {
+                Err(zmq::Error::EAGAIN) => (),
+                result => return result,
+            }
+            // Only fails once the runtime is shutting down
+            match self.events.readable().await {
+                Ok(mut guard) => guard.clear_ready(),
+                Err(_) => return Err(zmq::Error::ETERM),
+            }
+        }
+    }
+
+    // Only used on sockets whose sends never block
+    pub(crate) fn send(&self, data: &[u8]) -> zmq::Result<()> {
+        self.socket.send(data, 0)
+    }
+}
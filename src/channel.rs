@@ -0,0 +1,386 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use serde::Deserialize;
+use tokio::sync::Notify;
+
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 1024;
+
+// What happens to a message sent to a full channel, or to
+// data that doesn't fit in the outbound queue
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Overflow {
+    // Wait for room, which stops the daemon reading from the
+    // outbound socket so local clients are held back
+    Block,
+    // Drop the oldest message waiting in the channel or queue
+    DropOldest,
+    // Drop the message being sent
+    DropNewest,
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Overflow, String> {
+        match s {
+            "block" => Ok(Overflow::Block),
+            "drop_oldest" => Ok(Overflow::DropOldest),
+            "drop_newest" => Ok(Overflow::DropNewest),
+            _ => Err(format!(
+                "Unknown overflow policy {:?}, expected block, drop_oldest or drop_newest.",
+                s
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Overflow {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Overflow, String> {
+        s.parse()
+    }
+}
+
+// What a channel carries
+pub trait Item {
+    // Messages that change what the daemon does, like Close,
+    // are never dropped and may go over capacity
+    fn droppable(&self) -> bool;
+
+    // Messages that only wake the receiver up, one waiting is
+    // as good as many
+    fn signal(&self) -> bool {
+        false
+    }
+}
+
+// The receiver is gone, nothing sent is handled anymore
+#[derive(Debug)]
+pub struct Closed;
+
+impl fmt::Display for Closed {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "The channel is closed.")
+    }
+}
+
+// What the status reports about a channel
+#[derive(Default)]
+pub struct Counters {
+    dropped: AtomicU64,
+}
+
+impl Counters {
+    // Messages dropped since the daemon started
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    // For messages dropped after they left the channel, like
+    // those that didn't fit in the outbound queue
+    pub(crate) fn add_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+struct Shared<T> {
+    items: Mutex<VecDeque<T>>,
+    capacity: usize,
+    overflow: Overflow,
+    counters: Arc<Counters>,
+    // Signaled when something is sent
+    readable: Notify,
+    // Signaled when something is received
+    writable: Notify,
+    senders: AtomicUsize,
+    closed: AtomicBool,
+}
+
+// A channel holding up to capacity messages that can be
+// dropped
+pub fn channel<T: Item>(capacity: usize, overflow: Overflow) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        items: Mutex::new(VecDeque::with_capacity(capacity)),
+        capacity,
+        overflow,
+        counters: Arc::new(Counters::default()),
+        readable: Notify::new(),
+        writable: Notify::new(),
+        senders: AtomicUsize::new(1),
+        closed: AtomicBool::new(false),
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Item> Sender<T> {
+    // Applies the overflow policy, waiting for room if it's
+    // Overflow::Block. Returns the message that was dropped to
+    // make room, if any.
+    pub async fn send(&self, item: T) -> Result<Option<T>, Closed> {
+        let mut item = item;
+        loop {
+            let notified = self.shared.writable.notified();
+            match self.try_send(item, true)? {
+                Attempt::Done(dropped) => return Ok(dropped),
+                Attempt::Full(i) => item = i,
+            }
+            notified.await;
+        }
+    }
+
+    // Never waits, a full channel under Overflow::Block drops
+    // the message being sent
+    pub fn push(&self, item: T) -> Result<Option<T>, Closed> {
+        match self.try_send(item, false)? {
+            Attempt::Done(dropped) => Ok(dropped),
+            Attempt::Full(item) => Ok(Some(item)),
+        }
+    }
+
+    pub fn counters(&self) -> Arc<Counters> {
+        self.shared.counters.clone()
+    }
+
+    pub fn overflow(&self) -> Overflow {
+        self.shared.overflow
+    }
+
+    fn try_send(&self, item: T, wait: bool) -> Result<Attempt<T>, Closed> {
+        if self.shared.closed.load(Ordering::SeqCst) {
+            return Err(Closed);
+        }
+
+        let mut items = self.shared.items.lock().unwrap();
+        if item.signal() && items.iter().any(Item::signal) {
+            return Ok(Attempt::Done(None));
+        }
+
+        let dropped = if !item.droppable() || items.len() < self.shared.capacity {
+            items.push_back(item);
+            None
+        } else {
+            match self.shared.overflow {
+                Overflow::Block if wait => return Ok(Attempt::Full(item)),
+                Overflow::Block | Overflow::DropNewest => Some(item),
+                Overflow::DropOldest => match items.iter().position(Item::droppable) {
+                    Some(oldest) => {
+                        let oldest = items.remove(oldest);
+                        items.push_back(item);
+                        oldest
+                    },
+                    None => Some(item),
+                },
+            }
+        };
+        drop(items);
+
+        if dropped.is_some() {
+            self.shared.counters.add_dropped();
+        }
+        self.shared.readable.notify_one();
+        Ok(Attempt::Done(dropped))
+    }
+}
+
+enum Attempt<T> {
+    Done(Option<T>),
+    Full(T),
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.senders.fetch_add(1, Ordering::SeqCst);
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shared.readable.notify_one();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Item> Receiver<T> {
+    // Returns None once every sender is gone and nothing is
+    // left to receive
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            let notified = self.shared.readable.notified();
+            if let Some(item) = self.try_recv() {
+                self.shared.writable.notify_one();
+                return Some(item);
+            }
+            if self.shared.senders.load(Ordering::SeqCst) == 0 {
+                return None;
+            }
+            notified.await;
+        }
+    }
+
+    fn try_recv(&self) -> Option<T> {
+        self.shared.items.lock().unwrap().pop_front()
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+        // Senders waiting for room would wait forever
+        self.shared.writable.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[derive(Debug, PartialEq)]
+    enum Message {
+        Data(u32),
+        Close,
+        Wake,
+    }
+
+    impl Item for Message {
+        fn droppable(&self) -> bool {
+            matches!(self, Message::Data(_))
+        }
+
+        fn signal(&self) -> bool {
+            matches!(self, Message::Wake)
+        }
+    }
+
+    async fn received(receiver: &mut Receiver<Message>) -> Vec<Message> {
+        let mut items = Vec::new();
+        while let Ok(Some(item)) = timeout(Duration::from_millis(10), receiver.recv()).await {
+            items.push(item);
+        }
+        items
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let (sender, mut receiver) = channel(2, Overflow::Block);
+        sender.send(Message::Data(1)).await.unwrap();
+        sender.send(Message::Data(2)).await.unwrap();
+
+        let send = sender.send(Message::Data(3));
+        tokio::pin!(send);
+        assert!(timeout(Duration::from_millis(10), &mut send).await.is_err());
+
+        assert_eq!(receiver.recv().await, Some(Message::Data(1)));
+        assert_eq!(send.await.unwrap(), None);
+        assert_eq!(received(&mut receiver).await, vec![Message::Data(2), Message::Data(3)]);
+        assert_eq!(sender.counters().dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn block_push_drops_the_newest() {
+        let (sender, mut receiver) = channel(1, Overflow::Block);
+        assert_eq!(sender.push(Message::Data(1)).unwrap(), None);
+        assert_eq!(sender.push(Message::Data(2)).unwrap(), Some(Message::Data(2)));
+        assert_eq!(received(&mut receiver).await, vec![Message::Data(1)]);
+        assert_eq!(sender.counters().dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn drop_newest() {
+        let (sender, mut receiver) = channel(2, Overflow::DropNewest);
+        for i in 1..=3 {
+            sender.send(Message::Data(i)).await.unwrap();
+        }
+        assert_eq!(received(&mut receiver).await, vec![Message::Data(1), Message::Data(2)]);
+        assert_eq!(sender.counters().dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_skips_what_cant_be_dropped() {
+        let (sender, mut receiver) = channel(2, Overflow::DropOldest);
+        sender.send(Message::Close).await.unwrap();
+        sender.send(Message::Data(1)).await.unwrap();
+        assert_eq!(sender.send(Message::Data(2)).await.unwrap(), Some(Message::Data(1)));
+        assert_eq!(received(&mut receiver).await, vec![Message::Close, Message::Data(2)]);
+        assert_eq!(sender.counters().dropped(), 1);
+    }
+
+    #[tokio::test]
+    async fn never_drops_what_cant_be_dropped() {
+        for &overflow in &[Overflow::Block, Overflow::DropOldest, Overflow::DropNewest] {
+            let (sender, mut receiver) = channel(1, overflow);
+            sender.push(Message::Data(1)).unwrap();
+            sender.push(Message::Data(2)).unwrap();
+            assert_eq!(sender.send(Message::Close).await.unwrap(), None);
+            assert_eq!(sender.push(Message::Close).unwrap(), None);
+
+            let items = received(&mut receiver).await;
+            assert_eq!(items.iter().filter(|i| **i == Message::Close).count(), 2, "{:?}", overflow);
+        }
+    }
+
+    #[tokio::test]
+    async fn signals_are_coalesced() {
+        let (sender, mut receiver) = channel(4, Overflow::Block);
+        sender.send(Message::Wake).await.unwrap();
+        sender.send(Message::Data(1)).await.unwrap();
+        sender.send(Message::Wake).await.unwrap();
+        assert_eq!(received(&mut receiver).await, vec![Message::Wake, Message::Data(1)]);
+
+        sender.send(Message::Wake).await.unwrap();
+        assert_eq!(received(&mut receiver).await, vec![Message::Wake]);
+    }
+
+    #[tokio::test]
+    async fn dropping_the_receiver_wakes_senders() {
+        let (sender, receiver) = channel(1, Overflow::Block);
+        sender.send(Message::Data(1)).await.unwrap();
+
+        let send = sender.send(Message::Data(2));
+        tokio::pin!(send);
+        assert!(timeout(Duration::from_millis(10), &mut send).await.is_err());
+
+        drop(receiver);
+        assert!(timeout(Duration::from_millis(10), send).await.unwrap().is_err());
+        assert!(sender.push(Message::Data(3)).is_err());
+    }
+
+    #[tokio::test]
+    async fn dropping_the_last_sender_ends_the_channel() {
+        let (sender, mut receiver) = channel(2, Overflow::Block);
+        let other = sender.clone();
+        sender.send(Message::Data(1)).await.unwrap();
+        drop(sender);
+        other.send(Message::Data(2)).await.unwrap();
+
+        let recv = tokio::spawn(async move {
+            let mut items = Vec::new();
+            while let Some(item) = receiver.recv().await {
+                items.push(item);
+            }
+            items
+        });
+        tokio::task::yield_now().await;
+        drop(other);
+
+        let items = timeout(Duration::from_secs(1), recv).await.unwrap().unwrap();
+        assert_eq!(items, vec![Message::Data(1), Message::Data(2)]);
+    }
+}
@@ -0,0 +1,129 @@
+use std::convert::TryFrom;
+use std::io::{self, Write};
+use serde_json::Value;
+
+use herd_daemon::client::Subscription;
+use herd_daemon::ipc::{self, InboundFraming};
+use herd_daemon::models::{ClientMessage, ControlRequest, ControlResponse, Qos};
+
+// How long a client waits for messages to reach the daemon,
+// or for the daemon to answer, before giving up
+const TIMEOUT_MILLIS: i32 = 5000;
+
+// The sockets of a running daemon, as it was configured
+pub struct DaemonEndpoints {
+    pub outbound: String,
+    pub inbound: String,
+    pub control: String,
+    pub inbound_framing: InboundFraming,
+    // The daemon's sockets require CURVE, which the subcommands
+    // don't support
+    pub curve: bool,
+}
+
+pub fn send(
+    endpoints: &DaemonEndpoints,
+    topics: Vec<String>,
+    json: &str,
+    qos: Option<u8>,
+    id: Option<String>,
+) -> Result<(), String> {
+    let data: Value = match serde_json::from_str(json) {
+        Ok(d) => d,
+        Err(e) => return Err(format!("Invalid JSON {:?}: {}", json, e)),
+    };
+    let qos = match qos {
+        Some(q) => Qos::try_from(q)?,
+        None => Qos::default(),
+    };
+    push(endpoints, &ClientMessage::Data { topics, data, qos, id })
+}
+
+// Register, Unregister and Close
+pub fn push(endpoints: &DaemonEndpoints, message: &ClientMessage) -> Result<(), String> {
+    without_curve(endpoints)?;
+    let context = zmq::Context::new();
+    // Only queued once connected to the daemon, so sending fails
+    // if it can't be reached in time instead of the message
+    // being dropped when the socket is closed
+    let socket = connect(&context, zmq::PUSH, &endpoints.outbound, |socket| {
+        socket.set_immediate(true)?;
+        socket.set_sndtimeo(TIMEOUT_MILLIS)?;
+        socket.set_linger(TIMEOUT_MILLIS)
+    })?;
+    let message = serde_json::to_string(message).unwrap();
+    match socket.send(message.as_bytes(), 0) {
+        Ok(()) => Ok(()),
+        Err(zmq::Error::EAGAIN) => Err(format!("No daemon at {}.", endpoints.outbound)),
+        Err(e) => Err(format!("Error sending to {}: {}", endpoints.outbound, e)),
+    }
+}
+
+// Prints every message the daemon publishes, one JSON message
+// per line, or only those on topics starting with one of the
+// given ones
+pub fn subscribe(endpoints: &DaemonEndpoints, topics: &[String]) -> Result<(), String> {
+    without_curve(endpoints)?;
+    let context = zmq::Context::new();
+    let subscription = Subscription::connect(&context, &endpoints.inbound, endpoints.inbound_framing, topics)?;
+    loop {
+        let message = subscription.recv()?;
+        let mut stdout = io::stdout();
+        if writeln!(stdout, "{}", ipc::payload(&message)).and_then(|_| stdout.flush()).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+// Sends a request to the control socket and prints the
+// response
+pub fn control(endpoints: &DaemonEndpoints, request: &ControlRequest) -> Result<(), String> {
+    without_curve(endpoints)?;
+    let context = zmq::Context::new();
+    let socket = connect(&context, zmq::REQ, &endpoints.control, |socket| {
+        socket.set_rcvtimeo(TIMEOUT_MILLIS)?;
+        socket.set_sndtimeo(TIMEOUT_MILLIS)?;
+        socket.set_linger(0)
+    })?;
+
+    let request = serde_json::to_string(request).unwrap();
+    let reply = socket.send(request.as_bytes(), 0).and_then(|_| socket.recv_msg(0));
+    let reply = match reply {
+        Ok(r) => r,
+        Err(zmq::Error::EAGAIN) => return Err(format!("No reply from the daemon at {}.", endpoints.control)),
+        Err(e) => return Err(format!("Error talking to {}: {}", endpoints.control, e)),
+    };
+    let reply = String::from_utf8_lossy(&reply);
+    println!("{}", reply);
+
+    match serde_json::from_str::<ControlResponse>(&reply) {
+        Ok(ControlResponse::Error { reason }) => Err(reason),
+        _ => Ok(()),
+    }
+}
+
+fn without_curve(endpoints: &DaemonEndpoints) -> Result<(), String> {
+    if endpoints.curve {
+        return Err("The daemon's sockets require CURVE, which the client subcommands don't support.".to_owned());
+    }
+    Ok(())
+}
+
+// Options are set before connecting, some only apply to
+// connections made after them
+fn connect<F>(context: &zmq::Context, socket_type: zmq::SocketType, endpoint: &str, configure: F) -> Result<zmq::Socket, String>
+where
+    F: FnOnce(&zmq::Socket) -> zmq::Result<()>,
+{
+    let socket = match context.socket(socket_type) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Error creating socket: {}", e)),
+    };
+    if let Err(e) = configure(&socket) {
+        return Err(format!("Error configuring socket: {}", e));
+    }
+    match socket.connect(endpoint) {
+        Ok(()) => Ok(socket),
+        Err(e) => Err(format!("Error connecting to {}: {}", endpoint, e)),
+    }
+}
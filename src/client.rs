@@ -0,0 +1,81 @@
+use crate::ipc::{self, InboundFraming};
+use crate::models::InboundMessage;
+
+// Messages published on a daemon's inbound socket, only those
+// on topics starting with one of the given ones if any are
+pub struct Subscription {
+    socket: zmq::Socket,
+    framing: InboundFraming,
+    topics: Vec<String>,
+}
+
+impl Subscription {
+    // With topic framing the socket does the filtering, without
+    // topic frames every message has to be looked at
+    pub fn connect(
+        context: &zmq::Context,
+        endpoint: &str,
+        framing: InboundFraming,
+        topics: &[String],
+    ) -> Result<Subscription, String> {
+        let socket = match context.socket(zmq::SUB) {
+            Ok(s) => s,
+            Err(e) => return Err(format!("Error creating socket: {}", e)),
+        };
+        if let Err(e) = socket.connect(endpoint) {
+            return Err(format!("Error connecting to {}: {}", endpoint, e));
+        }
+
+        let filters = match framing {
+            InboundFraming::Topic if !topics.is_empty() => topics.to_vec(),
+            _ => vec![String::new()],
+        };
+        for filter in &filters {
+            if let Err(e) = socket.set_subscribe(filter.as_bytes()) {
+                return Err(format!("Error subscribing to {:?}: {}", filter, e));
+            }
+        }
+
+        Ok(Subscription {
+            socket,
+            framing,
+            topics: topics.to_vec(),
+        })
+    }
+
+    // Blocks until the next message. The daemon publishes Close
+    // before it exits.
+    pub fn recv(&self) -> Result<InboundMessage, String> {
+        loop {
+            let mut frames = match self.socket.recv_multipart(0) {
+                Ok(f) => f,
+                Err(e) => return Err(format!("Error receiving message: {}", e)),
+            };
+            let payload = match frames.pop() {
+                Some(p) => String::from_utf8_lossy(&p).into_owned(),
+                None => continue,
+            };
+
+            // Data from the server isn't tagged like the rest
+            let message = serde_json::from_str::<InboundMessage>(&payload)
+                .unwrap_or(InboundMessage::Data(payload));
+            if self.framing == InboundFraming::Topic || self.matches(&message) {
+                return Ok(message);
+            }
+        }
+    }
+
+    fn matches(&self, message: &InboundMessage) -> bool {
+        self.topics.is_empty() || ipc::topics(message)
+            .iter()
+            .any(|t| self.topics.iter().any(|filter| t.starts_with(filter.as_str())))
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = InboundMessage;
+
+    fn next(&mut self) -> Option<InboundMessage> {
+        self.recv().ok()
+    }
+}
@@ -0,0 +1,340 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use serde::Deserialize;
+use log::LevelFilter;
+use url::Url;
+use zeroize::Zeroizing;
+
+use crate::channel::Overflow;
+use crate::curve::parse_public_key;
+use crate::endpoints::FileMode;
+use crate::identity::IdentitySource;
+use crate::ipc::InboundFraming;
+use crate::logging::LogFormat;
+use crate::queue::FsyncPolicy;
+use crate::retry::RetryStrategy;
+
+pub const DEFAULT_OUTBOUND_PORT: u16 = 5555;
+pub const DEFAULT_INBOUND_PORT: u16 = 5556;
+pub const DEFAULT_CONTROL_PORT: u16 = 5557;
+pub const DEFAULT_SERVER_URL: &str = "ws://localhost:8080/ws/";
+pub const SERVER_URL_ENV: &str = "HERD_SERVER_URL";
+pub const API_KEY_ENV: &str = "HERD_API_KEY";
+pub const DEFAULT_STATE_DIR: &str = "/var/lib/herd-daemon";
+pub const DEFAULT_STDOUT_FILE: &str = "/tmp/herd-daemon.out";
+pub const DEFAULT_STDERR_FILE: &str = "/tmp/herd-daemon.err";
+pub const DEFAULT_SHUTDOWN_TIMEOUT_MILLIS: u64 = 5000;
+
+// Settings that can be provided through the file passed
+// with --config. Every field is optional. Command line
+// arguments win over the file, which wins over the built in
+// defaults. HERD_SERVER_URL and HERD_API_KEY, the only
+// settings read from the environment, sit between the command
+// line and the file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub account_id: Option<String>,
+    // Wiped from memory once the config is dropped
+    pub api_key: Option<Zeroizing<String>>,
+    pub api_key_file: Option<String>,
+    pub device_type_id: Option<String>,
+    pub device_id: Option<String>,
+    pub identity: Option<IdentitySource>,
+    pub interface: Option<String>,
+    pub outbound_port: Option<u16>,
+    pub inbound_port: Option<u16>,
+    pub outbound_endpoint: Option<String>,
+    pub inbound_endpoint: Option<String>,
+    pub control_port: Option<u16>,
+    pub control_endpoint: Option<String>,
+    pub inbound_framing: Option<InboundFraming>,
+    pub socket_owner: Option<String>,
+    pub socket_group: Option<String>,
+    pub socket_mode: Option<FileMode>,
+    pub curve_secret_key_file: Option<String>,
+    pub curve_allowed_keys: Option<Vec<String>>,
+    pub curve_allowed_keys_file: Option<String>,
+    pub server_url: Option<String>,
+    pub ca_file: Option<String>,
+    pub system_roots: Option<bool>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub retry_strategy: Option<RetryStrategy>,
+    pub retry_base_millis: Option<u64>,
+    pub retry_max_millis: Option<u64>,
+    pub max_retries: Option<u32>,
+    pub state_dir: Option<String>,
+    pub queue_max_bytes: Option<u64>,
+    pub queue_max_age_secs: Option<u64>,
+    pub queue_fsync: Option<FsyncPolicy>,
+    pub max_inflight: Option<usize>,
+    pub channel_capacity: Option<usize>,
+    pub channel_overflow: Option<Overflow>,
+    pub persist_topics: Option<bool>,
+    pub foreground: Option<bool>,
+    pub stdout_file: Option<String>,
+    pub stderr_file: Option<String>,
+    pub pid_file: Option<String>,
+    pub working_directory: Option<String>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub log_level: Option<LevelFilter>,
+    pub log_format: Option<LogFormat>,
+    pub shutdown_timeout_millis: Option<u64>,
+}
+
+impl FileConfig {
+    pub fn load(path: &str) -> Result<FileConfig, String> {
+        let contents = match fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) => return Err(format!("Error reading config file {}: {}", path, e)),
+        };
+
+        let config: FileConfig = match toml::from_str(&contents) {
+            Ok(c) => c,
+            Err(e) => return Err(format!("Error parsing config file {}: {}", path, e)),
+        };
+
+        match config.validate() {
+            Ok(()) => Ok(config),
+            Err(e) => Err(format!("Error in config file {}: {}", path, e)),
+        }
+    }
+
+    // Checks the values that parse but make no sense, naming the
+    // key at fault. Types are already checked while parsing.
+    fn validate(&self) -> Result<(), String> {
+        for (key, value) in &[
+            ("account_id", &self.account_id),
+            ("device_type_id", &self.device_type_id),
+            ("device_id", &self.device_id),
+            ("interface", &self.interface),
+            ("socket_owner", &self.socket_owner),
+            ("socket_group", &self.socket_group),
+        ] {
+            if let Some(value) = value {
+                if value.trim().is_empty() {
+                    return Err(format!("{} can't be empty.", key));
+                }
+            }
+        }
+
+        if let Some(api_key) = &self.api_key {
+            if api_key.trim().is_empty() {
+                return Err("api_key can't be empty.".to_owned());
+            }
+        }
+
+        if self.api_key.is_some() && self.api_key_file.is_some() {
+            return Err("Set either api_key or api_key_file, not both.".to_owned());
+        }
+
+        if let Some(url) = &self.server_url {
+            if let Err(e) = parse_server_url(url) {
+                return Err(format!("server_url: {}", e));
+            }
+        }
+
+        for (endpoint_key, endpoint, port_key, port) in &[
+            ("outbound_endpoint", &self.outbound_endpoint, "outbound_port", self.outbound_port),
+            ("inbound_endpoint", &self.inbound_endpoint, "inbound_port", self.inbound_port),
+            ("control_endpoint", &self.control_endpoint, "control_port", self.control_port),
+        ] {
+            if let Some(endpoint) = endpoint {
+                if port.is_some() {
+                    return Err(format!("Set either {} or {}, not both.", endpoint_key, port_key));
+                }
+                if !endpoint.contains("://") {
+                    return Err(format!(
+                        "{}: {:?} is not an endpoint, expected something like tcp://127.0.0.1:5555.",
+                        endpoint_key,
+                        endpoint
+                    ));
+                }
+            }
+        }
+
+        if let Some(keys) = &self.curve_allowed_keys {
+            for key in keys {
+                if let Err(e) = parse_public_key(key) {
+                    return Err(format!("curve_allowed_keys: {}", e));
+                }
+            }
+        }
+        if self.curve_secret_key_file.is_none()
+            && (self.curve_allowed_keys.is_some() || self.curve_allowed_keys_file.is_some())
+        {
+            return Err("CURVE allowed keys are set but curve_secret_key_file isn't.".to_owned());
+        }
+
+        for (first_key, first, second_key, second) in &[
+            ("outbound_port", self.outbound_port, "inbound_port", self.inbound_port),
+            ("outbound_port", self.outbound_port, "control_port", self.control_port),
+            ("inbound_port", self.inbound_port, "control_port", self.control_port),
+        ] {
+            if let (Some(first), Some(second)) = (first, second) {
+                if first == second {
+                    return Err(format!("{} and {} must differ, both are {}.", first_key, second_key, first));
+                }
+            }
+        }
+
+        if self.retry_base_millis == Some(0) {
+            return Err("retry_base_millis must be greater than zero.".to_owned());
+        }
+        if let (Some(base), Some(max)) = (self.retry_base_millis, self.retry_max_millis) {
+            if max < base {
+                return Err(format!("retry_max_millis ({}) can't be less than retry_base_millis ({}).", max, base));
+            }
+        }
+
+        if self.queue_max_bytes == Some(0) {
+            return Err("queue_max_bytes must be greater than zero.".to_owned());
+        }
+        if self.max_inflight == Some(0) {
+            return Err("max_inflight must be greater than zero.".to_owned());
+        }
+        if self.channel_capacity == Some(0) {
+            return Err("channel_capacity must be greater than zero.".to_owned());
+        }
+        Ok(())
+    }
+}
+
+// Picks the server url from the command line, then the
+// environment, then the config file, then the default.
+pub fn resolve_server_url(cli: Option<&str>, file: &FileConfig) -> Result<Url, String> {
+    let raw = match cli {
+        Some(url) => url.to_owned(),
+        None => match env::var(SERVER_URL_ENV) {
+            Ok(url) => url,
+            Err(_) => match &file.server_url {
+                Some(url) => url.clone(),
+                None => DEFAULT_SERVER_URL.to_owned(),
+            },
+        },
+    };
+    parse_server_url(&raw)
+}
+
+// Picks the endpoint a local socket binds to. A port on its
+// own is only reachable from the device itself.
+pub fn resolve_endpoint(
+    cli_endpoint: Option<&str>,
+    cli_port: Option<u16>,
+    file_endpoint: Option<&str>,
+    file_port: Option<u16>,
+    default_port: u16,
+) -> String {
+    match (cli_endpoint, cli_port, file_endpoint, file_port) {
+        (Some(endpoint), _, _, _) => endpoint.to_owned(),
+        (None, Some(port), _, _) => loopback_endpoint(port),
+        (None, None, Some(endpoint), _) => endpoint.to_owned(),
+        (None, None, None, Some(port)) => loopback_endpoint(port),
+        (None, None, None, None) => loopback_endpoint(default_port),
+    }
+}
+
+pub fn loopback_endpoint(port: u16) -> String {
+    format!("tcp://127.0.0.1:{}", port)
+}
+
+// Picks the api key from the command line, then the
+// environment, then the config file. Either source can point
+// to a file holding the key instead.
+pub fn resolve_api_key(
+    cli_key: Option<&str>,
+    cli_file: Option<&str>,
+    file: &FileConfig,
+) -> Result<Zeroizing<String>, String> {
+    match (cli_key, cli_file) {
+        (Some(_), Some(_)) => return Err("Pass either --api_key or --api_key_file, not both.".to_owned()),
+        (Some(key), None) => return Ok(Zeroizing::new(key.to_owned())),
+        (None, Some(path)) => return read_secret_file(path, "api key"),
+        (None, None) => (),
+    };
+
+    if let Ok(key) = env::var(API_KEY_ENV) {
+        let key = Zeroizing::new(key);
+        if key.trim().is_empty() {
+            return Err(format!("{} is set but empty.", API_KEY_ENV));
+        }
+        return Ok(key);
+    }
+
+    match (&file.api_key, &file.api_key_file) {
+        (Some(key), _) => Ok(key.clone()),
+        (None, Some(path)) => read_secret_file(path, "api key"),
+        (None, None) => Err(format!(
+            "Missing api_key, pass --api_key or --api_key_file, set {} or set api_key or api_key_file in the config file.",
+            API_KEY_ENV
+        )),
+    }
+}
+
+// The file must only be readable by its owner, the secret is
+// everything in it up to the first line break
+pub fn read_secret_file(path: &str, name: &str) -> Result<Zeroizing<String>, String> {
+    // Checked and read through the same handle, so the file
+    // can't be swapped in between
+    let mut file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) => return Err(format!("Error reading {} file {}: {}", name, path, e)),
+    };
+    let metadata = match file.metadata() {
+        Ok(m) => m,
+        Err(e) => return Err(format!("Error reading {} file {}: {}", name, path, e)),
+    };
+    let mode = metadata.permissions().mode();
+    if mode & 0o044 != 0 {
+        return Err(format!(
+            "{} file {} can be read by other users (mode {:o}), restrict it with chmod 600.",
+            capitalize(name),
+            path,
+            mode & 0o777
+        ));
+    }
+
+    // Sized up front so no copy of the secret is left behind
+    // by growing the buffer
+    let mut contents = Zeroizing::new(String::with_capacity(metadata.len() as usize + 1));
+    if let Err(e) = file.read_to_string(&mut contents) {
+        return Err(format!("Error reading {} file {}: {}", name, path, e));
+    }
+    let secret = contents.lines().next().unwrap_or("").trim();
+    if secret.is_empty() {
+        return Err(format!("{} file {} is empty.", capitalize(name), path));
+    }
+    Ok(Zeroizing::new(secret.to_owned()))
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+pub fn parse_server_url(raw: &str) -> Result<Url, String> {
+    let url = match Url::parse(raw) {
+        Ok(u) => u,
+        Err(e) => return Err(format!("Invalid server url {:?}: {}.", raw, e)),
+    };
+
+    match url.scheme() {
+        "ws" | "wss" => (),
+        scheme => return Err(
+            format!("Invalid server url {:?}: scheme must be ws or wss, found {:?}.", raw, scheme)
+        ),
+    };
+
+    if url.host_str().is_none() {
+        return Err(format!("Invalid server url {:?}: missing host.", raw));
+    }
+
+    Ok(url)
+}
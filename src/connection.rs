@@ -1,293 +1,691 @@
-use std::sync::mpsc::{Sender, Receiver};
-use websocket::ClientBuilder;
-use websocket::header::{Header, HeaderFormat, Headers, Authorization, Basic};
-use websocket::{OwnedMessage, Message};
-use hyper::header::parsing::from_one_raw_str;
-use std::{thread, time};
-use std::thread::JoinHandle;
 use std::fmt;
-use std::collections::HashSet;
+use std::io;
 use std::sync::{Arc, Mutex};
-use std::iter::FromIterator;
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use futures_util::{SinkExt, StreamExt};
+use native_tls::TlsConnector;
+use tokio::net::TcpStream;
+use tokio::task::{self, JoinHandle};
+use tokio::time::{self, Instant, MissedTickBehavior};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::error::TlsError;
+use tokio_tungstenite::tungstenite::handshake::client::Request as ClientRequest;
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, AUTHORIZATION};
+use tokio_tungstenite::tungstenite::{Error as WebSocketError, Message};
+use tokio_tungstenite::{Connector, MaybeTlsStream, WebSocketStream};
+use url::Url;
+use zeroize::Zeroizing;
+use log::{error, warn, info, debug, trace};
 
-use crate::models::{Request, ClientInformation, InboundMessage, Event};
-use crate::utils::maybe_error;
+use crate::channel::{Sender, Receiver};
+use crate::models::{Request, ClientInformation, InboundMessage, Event, ServerMessage, AckStatus, ConnectionState};
+use crate::queue::DiskQueue;
+use crate::subscriptions::{unanswered, Subscriptions};
+use crate::retry::{RetryPolicy, RetryState};
+use crate::tls::TlsOptions;
+use crate::utils::{maybe_error, acknowledge, reject, blocking};
 
-#[derive(Debug, Clone)]
-struct DeviceIdHeader(String);
+type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
-impl HeaderFormat for DeviceIdHeader {
-    fn fmt_header(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        let DeviceIdHeader(ref value) = *self;
-        write!(fmt, "{}", value)
+// How long connecting, including the TLS handshake and the
+// upgrade, may take before the attempt counts as failed
+const CONNECT_TIMEOUT_MILLIS: u64 = 10_000;
+// How long writing a frame may take before the connection is
+// considered lost
+const SEND_TIMEOUT_MILLIS: u64 = 10_000;
+// How long to wait for the server to answer a close frame
+const CLOSE_TIMEOUT_MILLIS: u64 = 2000;
+// How often topics the server hasn't confirmed are registered
+// again
+const REGISTER_RETRY_MILLIS: u64 = 30_000;
+
+impl ClientInformation {
+    pub fn new<'a>(
+        device_id: &'a str,
+        device_type_id: &'a str,
+        account_id: &'a str,
+        api_key: Zeroizing<String>,
+        server_url: Url,
+        tls_connector: Option<TlsConnector>,
+        tls_options: TlsOptions,
+    ) -> ClientInformation {
+        ClientInformation {
+            device_id: device_id.to_owned(),
+            device_type_id: device_type_id.to_owned(),
+            account_id: account_id.to_owned(),
+            api_key,
+            server_url,
+            tls_connector,
+            tls_options,
+        }
     }
-}
 
-impl Header for DeviceIdHeader {
-    fn header_name() -> &'static str {
-        "device-id"
+    // Whether a connection made with other would be the same as
+    // one made with this
+    fn same_connection(&self, other: &ClientInformation) -> bool {
+        self.device_id == other.device_id
+            && self.device_type_id == other.device_type_id
+            && self.account_id == other.account_id
+            && *self.api_key == *other.api_key
+            && self.server_url == other.server_url
+            && self.tls_options == other.tls_options
     }
 
-    fn parse_header(raw: &[Vec<u8>]) -> hyper::Result<DeviceIdHeader> {
-        from_one_raw_str(raw).map(DeviceIdHeader)
+    // The upgrade request, authenticated with the account id and
+    // api key
+    fn request(&self) -> Result<ClientRequest, ConnectionError> {
+        let mut request = match self.server_url.as_str().into_client_request() {
+            Ok(r) => r,
+            Err(e) => return Err(ConnectionError::Connect(Box::new(e))),
+        };
+
+        let credentials = Zeroizing::new(format!("{}:{}", self.account_id, *self.api_key));
+        let authorization = Zeroizing::new(format!("Basic {}", BASE64.encode(credentials.as_bytes())));
+        let headers = (
+            HeaderValue::from_str(&authorization),
+            HeaderValue::from_str(&self.device_id),
+            HeaderValue::from_str(&self.device_type_id),
+        );
+        let (mut authorization, device_id, device_type_id) = match headers {
+            (Ok(a), Ok(d), Ok(t)) => (a, d, t),
+            _ => return Err(ConnectionError::Setup("Invalid characters in the credentials.")),
+        };
+        // Kept out of debug output
+        authorization.set_sensitive(true);
+
+        let headers = request.headers_mut();
+        headers.insert(AUTHORIZATION, authorization);
+        headers.insert("device-id", device_id);
+        headers.insert("device-type-id", device_type_id);
+        Ok(request)
     }
 }
 
-#[derive(Debug, Clone)]
-struct DeviceTypeIdHeader(String);
+#[derive(Debug)]
+enum ConnectionError {
+    // The server could not be reached or refused the upgrade
+    Connect(Box<WebSocketError>),
+    // The TLS handshake failed, usually a certificate that
+    // could not be verified or a rejected client certificate
+    Tls(Box<WebSocketError>),
+    // The server didn't answer in time
+    Timeout,
+    Setup(&'static str),
+}
 
-impl HeaderFormat for DeviceTypeIdHeader {
-    fn fmt_header(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        let DeviceTypeIdHeader(ref value) = *self;
-        write!(fmt, "{}", value)
+impl ConnectionError {
+    fn from_connect(error: WebSocketError) -> ConnectionError {
+        match error {
+            WebSocketError::Tls(_) => ConnectionError::Tls(Box::new(error)),
+            _ => ConnectionError::Connect(Box::new(error)),
+        }
     }
 }
 
-impl Header for DeviceTypeIdHeader {
-    fn header_name() -> &'static str {
-        "device-type-id"
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectionError::Connect(e) => write!(fmt, "Error connecting to server: {}", e),
+            ConnectionError::Tls(e) => match &**e {
+                WebSocketError::Tls(TlsError::Native(e)) => write!(fmt, "TLS error: {}", e),
+                e => write!(fmt, "TLS error: {}", e),
+            },
+            ConnectionError::Timeout => write!(fmt, "Timed out connecting to server."),
+            ConnectionError::Setup(e) => write!(fmt, "{}", e),
+        }
     }
+}
 
-    fn parse_header(raw: &[Vec<u8>]) -> hyper::Result<DeviceTypeIdHeader> {
-        from_one_raw_str(raw).map(DeviceTypeIdHeader)
+fn retries_display(retry_state: &RetryState, retry_policy: &RetryPolicy) -> String {
+    match retry_policy.max_retries {
+        Some(max) => format!("{}/{}", retry_state.attempt, max),
+        None => format!("{}", retry_state.attempt),
     }
 }
 
-impl ClientInformation {
-    pub fn new<'a>(device_id: &'a str, device_type_id: &'a str, account_id: &'a str, api_key: &'a str) -> ClientInformation {
-        ClientInformation {
-            device_id: device_id.to_owned(),
-            device_type_id: device_type_id.to_owned(),
-            account_id: account_id.to_owned(),
-            api_key: api_key.to_owned(),
+// How a connection ended
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    // Closed on purpose, nothing left to do
+    Close,
+    // Lost, reconnect after the retry delay
+    Restart,
+    // The settings changed, reconnect right away
+    Reconnect,
+}
+
+// What the connection is made with. Replaced as a whole when
+// the configuration is reloaded.
+#[derive(Clone)]
+pub struct Settings {
+    pub client_information: ClientInformation,
+    pub retry_policy: RetryPolicy,
+    // How long pending events are given to be sent on close
+    pub shutdown_timeout: Duration,
+}
+
+// What the control socket reports about the connection
+pub struct ConnectionStatus {
+    pub state: ConnectionState,
+    pub attempt: u32,
+    pub max_retries: Option<u32>,
+    pub device_id: String,
+    pub server_url: String,
+    pub connected_since: Option<std::time::Instant>,
+}
+
+impl ConnectionStatus {
+    pub fn new(settings: &Settings) -> ConnectionStatus {
+        ConnectionStatus {
+            state: ConnectionState::Connecting,
+            attempt: 0,
+            max_retries: settings.retry_policy.max_retries,
+            device_id: settings.client_information.device_id.clone(),
+            server_url: settings.client_information.server_url.to_string(),
+            connected_since: None,
+        }
+    }
+
+    fn update(&mut self, state: ConnectionState, retry_state: &RetryState, settings: &Settings) {
+        if state != ConnectionState::Connected {
+            self.connected_since = None;
+        } else if self.state != ConnectionState::Connected {
+            self.connected_since = Some(std::time::Instant::now());
         }
+        self.state = state;
+        self.attempt = retry_state.attempt;
+        self.max_retries = settings.retry_policy.max_retries;
+        self.device_id = settings.client_information.device_id.clone();
+        self.server_url = settings.client_information.server_url.to_string();
     }
 }
 
-const MAX_RETRIES: u32 = 10;
-// TODO: change back to 5000 when ready
-const RETRY_SLEEP_DURATION_MILLIS: u64 = 1000;
+// Reads the configuration again
+pub type Reload = Arc<dyn Fn() -> Result<Settings, String> + Send + Sync>;
+
+// Everything the connection task owns. Requests from local
+// clients, frames from the server and shutdown are all handled
+// by this one task.
+struct Connection {
+    settings: Settings,
+    reload: Reload,
+    receiver: Receiver<Request>,
+    inbound_sender: Sender<InboundMessage>,
+    registered_topics: Arc<Mutex<Subscriptions>>,
+    queue: Arc<Mutex<DiskQueue>>,
+    status: Arc<Mutex<ConnectionStatus>>,
+    retry_state: RetryState,
+}
 
+// Starts the task keeping the connection to the server up until
+// it's closed, must be called from within a LocalSet
 pub fn initialize(
-    client_information: ClientInformation,
-    sender: Sender<Request>,
+    settings: Settings,
+    reload: Reload,
     receiver: Receiver<Request>,
     inbound_sender: Sender<InboundMessage>,
-    registered_topics: Arc<Mutex<HashSet::<String>>>,
+    registered_topics: Arc<Mutex<Subscriptions>>,
+    queue: Arc<Mutex<DiskQueue>>,
+    status: Arc<Mutex<ConnectionStatus>>,
 ) -> JoinHandle<()> {
-    let receiver_arc = Arc::new(Mutex::new(receiver));
-    thread::spawn(move || {
-        let mut retries = 0;
+    let connection = Connection {
+        settings,
+        reload,
+        receiver,
+        inbound_sender,
+        registered_topics,
+        queue,
+        status,
+        retry_state: RetryState::new(),
+    };
+    task::spawn_local(connection.run())
+}
+
+impl Connection {
+    async fn run(mut self) {
         loop {
-            // This seems to work as a basic restarting mechanism.
-            // Currently, if there is no server up, it looks like
-            // the websocket conenction (expectantly) fails, but
-            // there not sure how the threads exit then...  
-            let result = websocket(
-                client_information.clone(),
-                sender.clone(),
-                receiver_arc.clone(),
-                inbound_sender.clone(),
-                registered_topics.clone(),
-            );
-
-            let (sender_thread, receiver_thread) = match result {
-                Ok(x) => {
-                    retries = 0;
-                    x
+            self.set_state(ConnectionState::Connecting);
+            let outcome = match self.connect().await {
+                Ok(websocket) => {
+                    self.retry_state.reset();
+                    self.set_state(ConnectionState::Connected);
+                    self.session(websocket).await
                 },
-                Err(_) => {
-                    if retries < MAX_RETRIES {
-                        retries += 1;
-                        eprintln!(
-                            "Error starting websocket connection. Retries {}/{}.",
-                            retries,
-                            MAX_RETRIES
-                        );
-                        maybe_error(inbound_sender.send(InboundMessage::Restart));
-                        thread::sleep(time::Duration::from_millis(RETRY_SLEEP_DURATION_MILLIS));
-                        continue;
-                    } else {
-                        eprintln!(
-                            "Error starting websocket connection. Max retries ({}) exceeded.",
-                            MAX_RETRIES
-                        );
-                        maybe_error(inbound_sender.send(InboundMessage::Close));
+                Err(e) => {
+                    let retry_policy = self.settings.retry_policy.clone();
+                    let delay = match self.retry_state.next_delay(&retry_policy) {
+                        Some(d) => d,
+                        None => {
+                            error!(
+                                "Error starting websocket connection. Max retries ({}) exceeded.",
+                                self.retry_state.attempt
+                            );
+                            self.closed();
+                            return;
+                        }
+                    };
+                    self.set_state(ConnectionState::Reconnecting);
+                    match e {
+                        ConnectionError::Tls(_) => error!(
+                            "TLS verification with {} failed, check the CA bundle and client certificate. {} Retries {}, next attempt in {}ms.",
+                            self.settings.client_information.server_url,
+                            e,
+                            retries_display(&self.retry_state, &retry_policy),
+                            delay.as_millis()
+                        ),
+                        _ => warn!(
+                            "Error starting websocket connection to {}. {} Retries {}, next attempt in {}ms.",
+                            self.settings.client_information.server_url,
+                            e,
+                            retries_display(&self.retry_state, &retry_policy),
+                            delay.as_millis()
+                        ),
+                    };
+                    if !self.wait_to_reconnect(delay).await {
                         return;
                     }
+                    continue;
                 }
             };
-            let sender_output = match sender_thread.join() {
-                Ok(res) => res,
-                Err(e) => {
-                    println!("Error in joining sender: {:?}", e);
-                    false
+
+            match outcome {
+                Outcome::Close => {
+                    info!("Websocket connection closed, not restarting.");
+                    self.closed();
+                    return;
                 },
-            };
-            let receiver_output = match receiver_thread.join() {
-                Ok(res) => res,
-                Err(e) => {
-                    println!("Error in joining receiver: {:?}", e);
-                    false
+                Outcome::Reconnect => {
+                    info!("Reconnecting right away.");
+                    continue;
                 },
-            };
-            if !sender_output && !receiver_output {
-                println!("Returning websocket thread");
-                maybe_error(inbound_sender.send(InboundMessage::Close));
+                Outcome::Restart => (),
+            }
+            // The state was reset when the connection succeeded,
+            // so the policy always allows this first attempt.
+            let retry_policy = self.settings.retry_policy.clone();
+            let delay = self.retry_state.next_delay(&retry_policy).unwrap_or(retry_policy.base_delay);
+            warn!("Restarting websocket connection in {}ms.", delay.as_millis());
+            self.set_state(ConnectionState::Reconnecting);
+            if !self.wait_to_reconnect(delay).await {
                 return;
             }
-            println!("Restarting websocket connection...");
-            thread::sleep(time::Duration::from_millis(RETRY_SLEEP_DURATION_MILLIS));
         }
-    }) 
-}
+    }
 
-fn websocket(
-    client_information: ClientInformation,
-    sender: Sender<Request>,
-    receiver_arc: Arc<Mutex<Receiver<Request>>>,
-    inbound_sender: Sender<InboundMessage>,
-    registered_topics: Arc<Mutex<HashSet::<String>>>,
-) -> Result<(JoinHandle<bool>, JoinHandle<bool>), &'static str> {
-    let mut headers = Headers::new();
-    headers.set(
-        Authorization(
-            Basic {
-                username: client_information.account_id.clone(),
-                password: Some(client_information.api_key.clone()),
-            }
-        )
-    );
-    headers.set(DeviceIdHeader(client_information.device_id.clone()));
-    headers.set(DeviceTypeIdHeader(client_information.device_type_id.clone()));
-    let client = ClientBuilder::new("ws://localhost:8080/ws/")
-        .unwrap()
-        .custom_headers(&headers)
-        .connect_insecure();
-
-    let client = match client {
-        Ok(c) => c,
-        Err(_) => {
-            return Err("Error connecting to server.");
-        }
-    };
+    fn set_state(&self, state: ConnectionState) {
+        self.status.lock().unwrap().update(state, &self.retry_state, &self.settings);
+    }
 
-    let (mut client_receiver, mut client_sender) = match client.split() {
-        Ok(c) => c,
-        Err(_) => {
-            return Err("Error splitting client.");
-        }
-    };
+    fn closed(&self) {
+        self.set_state(ConnectionState::Closed);
+        maybe_error(self.inbound_sender.push(InboundMessage::Close));
+    }
+
+    async fn connect(&self) -> Result<WebSocket, ConnectionError> {
+        let client_information = &self.settings.client_information;
+        let request = client_information.request()?;
+        let connector = client_information.tls_connector.clone().map(Connector::NativeTls);
+        let connecting = tokio_tungstenite::connect_async_tls_with_config(request, None, false, connector);
+        let mut websocket = match time::timeout(Duration::from_millis(CONNECT_TIMEOUT_MILLIS), connecting).await {
+            Ok(Ok((websocket, _))) => websocket,
+            Ok(Err(e)) => return Err(ConnectionError::from_connect(e)),
+            Err(_) => return Err(ConnectionError::Timeout),
+        };
 
-    {
-        let mut topics = registered_topics.lock().unwrap();
-        // Send reregister event if a topic was registered
-        if topics.len() > 0 {
-            let json_string = serde_json::to_string(&Event::Register {
-                topics: Vec::from_iter(topics.drain()),
-            }).expect("Error parsing data.");
-            match client_sender.send_message(&OwnedMessage::Text(json_string)) {
-                Ok(()) => println!("Reregistering topics."),
-                Err(e) => eprintln!("{:?}", e),
+        // Send reregister event if a topic was registered. The
+        // topics stay pending until the server confirms them.
+        let topics = blocking(&self.registered_topics, |subscriptions| {
+            subscriptions.reset();
+            if subscriptions.is_empty() {
+                None
+            } else {
+                Some(subscriptions.topics())
+            }
+        }).await;
+        if let Some(topics) = topics {
+            let json_string = serde_json::to_string(&Event::Register { topics }).expect("Error parsing data.");
+            match send(&mut websocket, Message::Text(json_string)).await {
+                Ok(()) => info!("Reregistering topics."),
+                Err(e) => {
+                    error!("Error reregistering topics: {:?}", e);
+                    return Err(ConnectionError::Setup("Error reregistering topics."));
+                },
             };
         }
+        Ok(websocket)
     }
 
-    let sender_thread = thread::spawn(move || {
-        // Unwrapping and locking the receiver portion
-        // over the thread life should be fine as only one
-        // websocket connection is used at a time
-
-        let receiver = receiver_arc.lock().unwrap();
+    // Lets local clients know a reconnect is coming and waits it
+    // out. Returns false if the daemon was asked to close in the
+    // meantime.
+    async fn wait_to_reconnect(&mut self, delay: Duration) -> bool {
+        maybe_error(self.inbound_sender.push(InboundMessage::Restart {
+            attempt: self.retry_state.attempt,
+            max_retries: self.settings.retry_policy.max_retries,
+            next_delay_millis: delay.as_millis() as u64,
+        }));
 
+        let deadline = Instant::now() + delay;
         loop {
-            let request = receiver.recv().unwrap();
-
+            let request = match time::timeout_at(deadline, self.receiver.recv()).await {
+                Ok(Some(r)) => r,
+                Err(_) => return true,
+                Ok(None) => {
+                    time::sleep_until(deadline).await;
+                    return true;
+                },
+            };
             match request {
-                Request::Pong(data) => {
-                    match client_sender.send_message(&OwnedMessage::Pong(data)) {
-                        Ok(_) => println!("Successfully sent pong"),
-                        Err(e) => {
-                            // Should restart connection
-                            println!("Error sending pong: {:?}", e);
-                            let _ = client_sender.send_message(&Message::close());
-                            return true;
-                        }
-                    }
-                }
-                Request::Data(data) => {
-                    let json_string = serde_json::to_string(&data).expect("Error parsing data.");
-                    match client_sender.send_message(&OwnedMessage::Text(json_string)) {
-                        Ok(()) => println!("Sent message!"),
-                        Err(e) => println!("Send Loop: {:?}", e),
-                    };
-
+                // Whatever changed, the next attempt uses it
+                Request::Reload(client_id) => {
+                    self.apply_reload(&client_id);
+                    return true;
                 },
                 Request::Close => {
-                    println!("Close request received!!!!");
-                    match client_sender.send_message(&Message::close()) {
-                        Ok(_) => println!("Successfully closed connection"),
-                        Err(e) => println!("Error while closing connection: {:?}", e),
-                    };
-                    // this thread doesn't know if should restart, ask receiver_thread
+                    info!("Close requested while reconnecting.");
+                    self.closed();
                     return false;
                 },
+                // The subscriptions were already updated, and
+                // every topic is registered on connecting
+                Request::Data(_, client_id) => acknowledge(&self.inbound_sender, &client_id, AckStatus::Queued),
+                Request::Reconnect => return true,
+                // The queue is drained once the connection is
+                // back up
+                Request::Flush => (),
             }
         }
-    });
-
-    let receiver_thread = thread::spawn(move || {
-        // Unwrapping and locking should be fine over the
-        // duration of the thread life, given that there is only
-        // on thread handling the receiving from the socket
-        // let inbound_socket = inbound_socket_arc.lock().unwrap();
-
-        for message in client_receiver.incoming_messages() {
-            println!("message received: {:?}", message);
-            let message = match message {
-                Ok(m) => m,
-                Err(e) => {
-                    println!("Error in received message, closing connection: {:?}", e);
-                    let _ = sender.send(Request::Close);
-                    let _ = inbound_sender.send(InboundMessage::Close);
-                    // TODO: don't restart for now, if it gets here,
-                    // daemon was probably given a close code and the sender_thread
-                    // already send close
-                    return false;
-                }
-            };
+    }
 
-            match message {
-                OwnedMessage::Close(_) => {
-                    let _ = sender.send(Request::Close);
-                    // TODO: Depending on code, maybe restart
-                    return true;
-                },
-                OwnedMessage::Ping(data) => {
-                    match sender.send(Request::Pong(data)) {
-                        Ok(_) => (),
-                        Err(e) => {
-                            println!("Error in sending pong frame, closing connection: {:?}", e);
-                            let _  = sender.send(Request::Close);
-                            // If we unexpectedly can't send data, we never received a close code
-                            // in the first place, so notify to restart
-                            return true;
-                        }
-                    };
+    // Swaps in freshly loaded settings, returns true if they can
+    // only take effect on a new connection.
+    fn apply_reload(&mut self, client_id: &Option<String>) -> bool {
+        let new_settings = match (self.reload)() {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Error reloading configuration, keeping the current one. {}", e);
+                reject(&self.inbound_sender, client_id, e);
+                return false;
+            },
+        };
+
+        let reconnect = !self.settings.client_information.same_connection(&new_settings.client_information);
+        self.settings = new_settings;
+        info!("Configuration reloaded.");
+        acknowledge(&self.inbound_sender, client_id, AckStatus::Applied);
+        reconnect
+    }
+
+    // Handles frames from the server and requests from local
+    // clients as they come until the connection ends
+    async fn session(&mut self, mut websocket: WebSocket) -> Outcome {
+        // Send anything queued while the connection was down,
+        // starting with what the server never acknowledged
+        if let Err(e) = self.resend_unacknowledged(&mut websocket).await {
+            warn!("Error resending unacknowledged message: {:?}", e);
+            close(&mut websocket).await;
+            return Outcome::Restart;
+        }
+        if let Err(e) = self.drain_queue(&mut websocket, None).await {
+            warn!("Error sending queued message: {:?}", e);
+            close(&mut websocket).await;
+            return Outcome::Restart;
+        }
+
+        let period = Duration::from_millis(REGISTER_RETRY_MILLIS);
+        let mut register_retry = time::interval_at(Instant::now() + period, period);
+        register_retry.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut unconfirmed = Vec::new();
+        loop {
+            let outcome = tokio::select! {
+                frame = websocket.next() => self.handle_frame(&mut websocket, frame).await,
+                // Only gone once every local client is, nothing
+                // can ask for anything after that
+                request = self.receiver.recv() => {
+                    let request = request.unwrap_or(Request::Close);
+                    self.handle_request(&mut websocket, request).await
                 },
-                OwnedMessage::Text(data) => {
-                    println!("Received text message: {:?}", data);
-                    maybe_error(inbound_sender.send(InboundMessage::Data(data)));
+                _ = register_retry.tick() => self.retry_registrations(&mut websocket, &mut unconfirmed).await,
+            };
+            if let Some(outcome) = outcome {
+                return outcome;
+            }
+            // Everything runs on one thread, handing over after
+            // each frame or request lets the publisher keep up
+            // with what's sent to local clients
+            task::yield_now().await;
+        }
+    }
+
+    // Registers topics again that were already pending at the
+    // last check, the server may never have answered them
+    async fn retry_registrations(&self, websocket: &mut WebSocket, unconfirmed: &mut Vec<String>) -> Option<Outcome> {
+        let pending = blocking(&self.registered_topics, |subscriptions| subscriptions.pending()).await;
+        let topics = unanswered(&pending, unconfirmed);
+        *unconfirmed = pending;
+        if topics.is_empty() {
+            return None;
+        }
+
+        warn!("Topics {:?} weren't confirmed by the server, registering them again.", topics);
+        let json_string = serde_json::to_string(&Event::Register { topics }).expect("Error parsing data.");
+        if let Err(e) = send(websocket, Message::Text(json_string)).await {
+            warn!("Error reregistering topics: {:?}", e);
+            close(websocket).await;
+            return Some(Outcome::Restart);
+        }
+        None
+    }
+
+    async fn handle_request(&mut self, websocket: &mut WebSocket, request: Request) -> Option<Outcome> {
+        match request {
+            Request::Data(data, client_id) => {
+                let json_string = serde_json::to_string(&data).expect("Error parsing data.");
+                match send(websocket, Message::Text(json_string)).await {
+                    Ok(()) => {
+                        debug!("Sent event.");
+                        acknowledge(&self.inbound_sender, &client_id, AckStatus::Sent);
+                        None
+                    },
+                    Err(e) => {
+                        warn!("Error sending event: {:?}", e);
+                        close(websocket).await;
+                        Some(Outcome::Restart)
+                    },
+                }
+            },
+            Request::Flush => {
+                if let Err(e) = self.drain_queue(websocket, None).await {
+                    // The entry stays queued and is retried
+                    // once the connection is restarted
+                    warn!("Error sending queued message: {:?}", e);
+                    close(websocket).await;
+                    return Some(Outcome::Restart);
+                }
+                None
+            },
+            Request::Reload(client_id) => {
+                if self.apply_reload(&client_id) {
+                    info!("Connection settings changed, closing the connection.");
+                    close(websocket).await;
+                    return Some(Outcome::Reconnect);
+                }
+                None
+            },
+            Request::Reconnect => {
+                info!("Reconnect requested, closing the connection.");
+                close(websocket).await;
+                Some(Outcome::Reconnect)
+            },
+            Request::Close => {
+                info!("Closing websocket connection.");
+                // Whatever doesn't make it out in time stays
+                // queued on disk for the next start
+                let deadline = Instant::now() + self.settings.shutdown_timeout;
+                if let Err(e) = self.drain_queue(websocket, Some(deadline)).await {
+                    warn!("Error sending queued message while closing: {:?}", e);
+                }
+                close(websocket).await;
+                info!("Websocket connection closed.");
+                Some(Outcome::Close)
+            },
+        }
+    }
+
+    async fn handle_frame(
+        &mut self,
+        websocket: &mut WebSocket,
+        frame: Option<Result<Message, WebSocketError>>,
+    ) -> Option<Outcome> {
+        let message = match frame {
+            Some(Ok(m)) => m,
+            Some(Err(e)) => {
+                warn!("Error receiving frame, restarting connection: {:?}", e);
+                return Some(Outcome::Restart);
+            },
+            None => {
+                warn!("Connection ended by the server, restarting connection.");
+                return Some(Outcome::Restart);
+            },
+        };
+        trace!("Frame received: {:?}", message);
+
+        match message {
+            Message::Close(_) => {
+                // The answer is queued when the frame is read
+                info!("Server closed the connection.");
+                let _ = time::timeout(Duration::from_millis(CLOSE_TIMEOUT_MILLIS), websocket.flush()).await;
+                // TODO: Depending on code, maybe restart
+                Some(Outcome::Restart)
+            },
+            Message::Text(data) => {
+                trace!("Text frame received: {:?}", data);
+                match serde_json::from_str::<ServerMessage>(&data) {
+                    Ok(ServerMessage::Registered { topics }) => {
+                        let registrations = blocking(&self.registered_topics, move |subscriptions| {
+                            subscriptions.confirm(&topics);
+                            InboundMessage::Registrations {
+                                id: None,
+                                confirmed: subscriptions.confirmed(),
+                                pending: subscriptions.pending(),
+                            }
+                        }).await;
+                        maybe_error(self.inbound_sender.push(registrations));
+                    },
+                    Ok(ServerMessage::Ack { id }) => {
+                        let acknowledged = blocking(&self.queue, move |q| q.acknowledge(id)).await;
+                        match acknowledged {
+                            Ok(Some(entry)) => {
+                                acknowledge(&self.inbound_sender, &entry.client_id, AckStatus::Delivered);
+                                // Room may have opened up for queued events
+                                if let Err(e) = self.drain_queue(websocket, None).await {
+                                    warn!("Error sending queued message: {:?}", e);
+                                    close(websocket).await;
+                                    return Some(Outcome::Restart);
+                                }
+                            },
+                            Ok(None) => debug!("Ack received for unknown message {}.", id),
+                            Err(e) => error!("{}", e),
+                        };
+                    },
+                    Err(_) => maybe_error(self.inbound_sender.push(InboundMessage::Data(data))),
+                };
+                None
+            },
+            // Pings are answered by the websocket itself
+            Message::Ping(_) => {
+                trace!("Ping received.");
+                None
+            },
+            Message::Binary(data) => {
+                trace!("Ignoring binary frame: {:?}", data);
+                None
+            },
+            _ => {
+                trace!("Pong received.");
+                None
+            },
+        }
+    }
+
+    // Sends queued events oldest first, an entry is only removed
+    // from the queue once it has been written to the socket.
+    // Sending stops at the deadline if one is given.
+    async fn drain_queue(&self, websocket: &mut WebSocket, deadline: Option<Instant>) -> Result<(), WebSocketError> {
+        loop {
+            if let Some(deadline) = deadline {
+                if Instant::now() >= deadline {
+                    warn!("Ran out of time sending queued messages, the rest are sent on the next start.");
+                    return Ok(());
+                }
+            }
+            // The lock is never held while sending so the control
+            // socket can still report on the queue
+            let next = blocking(&self.queue, |queue| {
+                if queue.inflight_full() {
+                    return Ok(None);
+                }
+                queue.peek().map(|entry| entry.map(|e| {
+                    let expired = queue.is_expired(&e);
+                    (e, expired)
+                }))
+            }).await;
+            let entry = match next {
+                Ok(Some((e, false))) => e,
+                Ok(Some((e, true))) => {
+                    warn!("Dropping expired queued message.");
+                    reject(&self.inbound_sender, &e.client_id, "Expired in the outbound queue.".to_owned());
+                    maybe_error(blocking(&self.queue, DiskQueue::pop).await);
+                    continue;
                 },
-                OwnedMessage::Binary(data) => {
-                    println!("Received binary message: {:?}", data);
+                Ok(None) => return Ok(()),
+                Err(e) => {
+                    error!("{}", e);
+                    return Ok(());
                 },
-                _ => println!("Pong received"),
-            }
+            };
+
+            let json_string = serde_json::to_string(&entry.event).expect("Error parsing data.");
+            send(websocket, Message::Text(json_string)).await?;
+            debug!("Sent queued event.");
+            acknowledge(&self.inbound_sender, &entry.client_id, AckStatus::Sent);
+            maybe_error(blocking(&self.queue, move |q| q.sent(entry)).await);
         }
-        // Base case, don't instruct to restart
-        return false;
-    });
+    }
+
+    async fn resend_unacknowledged(&self, websocket: &mut WebSocket) -> Result<(), WebSocketError> {
+        let entries = blocking(&self.queue, |q| q.unacknowledged()).await;
+        for entry in entries {
+            let json_string = serde_json::to_string(&entry.event).expect("Error parsing data.");
+            send(websocket, Message::Text(json_string)).await?;
+        }
+        Ok(())
+    }
+}
+
+// Writes a frame, a server that doesn't take it in time is
+// treated like a lost connection
+async fn send(websocket: &mut WebSocket, message: Message) -> Result<(), WebSocketError> {
+    match time::timeout(Duration::from_millis(SEND_TIMEOUT_MILLIS), websocket.send(message)).await {
+        Ok(result) => result,
+        Err(_) => Err(WebSocketError::Io(io::Error::new(io::ErrorKind::TimedOut, "Timed out sending frame."))),
+    }
+}
 
-    Ok((sender_thread, receiver_thread))
-}
\ No newline at end of file
+// Sends a close frame and waits for the server to answer it
+async fn close(websocket: &mut WebSocket) {
+    let closing = async {
+        websocket.close(None).await?;
+        while let Some(frame) = websocket.next().await {
+            frame?;
+        }
+        Ok(())
+    };
+    match time::timeout(Duration::from_millis(CLOSE_TIMEOUT_MILLIS), closing).await {
+        Ok(Ok(())) | Ok(Err(WebSocketError::ConnectionClosed)) => (),
+        Ok(Err(e)) => debug!("Connection ended while closing: {:?}", e),
+        Err(_) => debug!("Server didn't answer the close frame."),
+    }
+}
@@ -0,0 +1,121 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::task::{self, JoinHandle};
+use log::{error, info, trace, warn};
+
+use crate::async_socket::AsyncSocket;
+use crate::channel::{Counters, Sender};
+use crate::connection::ConnectionStatus;
+use crate::queue::DiskQueue;
+use crate::subscriptions::Subscriptions;
+use crate::models::{ControlRequest, ControlResponse, Request, Status};
+use crate::utils::blocking;
+
+// What the control socket answers from
+#[derive(Clone)]
+pub struct ControlState {
+    pub sender: Sender<Request>,
+    // The connection's channel to the publisher
+    pub inbound_counters: Arc<Counters>,
+    pub ipc_socket: Arc<Mutex<zmq::Socket>>,
+    pub status: Arc<Mutex<ConnectionStatus>>,
+    pub registered_topics: Arc<Mutex<Subscriptions>>,
+    pub queue: Arc<Mutex<DiskQueue>>,
+    pub started: Instant,
+}
+
+// Answers requests on the REP socket until the daemon exits.
+// Every request gets exactly one JSON response. Must be called
+// from within a LocalSet.
+pub fn initialize(socket: AsyncSocket, state: ControlState) -> JoinHandle<()> {
+    task::spawn_local(serve(socket, state))
+}
+
+async fn serve(socket: AsyncSocket, state: ControlState) {
+    loop {
+        let message = match socket.recv().await {
+            Ok(m) => m,
+            Err(e) => {
+                error!("Error receiving control request: {}", e);
+                return;
+            },
+        };
+        trace!("Control request received: {:?}", message.as_str());
+
+        let request = match serde_json::from_slice::<ControlRequest>(&message) {
+            Ok(r) => Some(r),
+            Err(e) => {
+                warn!("Error deserializing control request: {}", e);
+                None
+            },
+        };
+        let response = match &request {
+            Some(r) => respond(r, &state).await,
+            None => ControlResponse::Error { reason: "Invalid control request.".to_owned() },
+        };
+
+        if let Err(e) = socket.send(serde_json::to_string(&response).unwrap().as_bytes()) {
+            error!("Error sending control response: {}", e);
+        }
+
+        // Answered first, the socket is gone once the daemon exits
+        if let Some(ControlRequest::Shutdown) = request {
+            info!("Shutdown requested on the control socket.");
+            if let Err(e) = crate::signals::request_close(&state.ipc_socket) {
+                error!("Error requesting close: {}", e);
+            }
+        }
+    }
+}
+
+// Waits on the queue's lock, which the runtime can't
+pub fn status(state: &ControlState) -> Status {
+    let (queue_depth, inflight) = {
+        let queue = state.queue.lock().unwrap();
+        (queue.len(), queue.inflight_len())
+    };
+    report(state, queue_depth, inflight)
+}
+
+fn report(state: &ControlState, queue_depth: usize, inflight: usize) -> Status {
+    let outbound_counters = state.sender.counters();
+    let status = state.status.lock().unwrap();
+    Status {
+        state: status.state,
+        device_id: status.device_id.clone(),
+        server_url: status.server_url.clone(),
+        attempt: status.attempt,
+        max_retries: status.max_retries,
+        uptime_secs: state.started.elapsed().as_secs(),
+        connected_secs: status.connected_since.map(|since| since.elapsed().as_secs()),
+        queue_depth,
+        inflight,
+        outbound_dropped: outbound_counters.dropped(),
+        inbound_dropped: state.inbound_counters.dropped(),
+    }
+}
+
+async fn respond(request: &ControlRequest, state: &ControlState) -> ControlResponse {
+    match request {
+        ControlRequest::Status => {
+            let (queue_depth, inflight) = blocking(&state.queue, |q| (q.len(), q.inflight_len())).await;
+            ControlResponse::Status(report(state, queue_depth, inflight))
+        },
+        ControlRequest::Topics => blocking(&state.registered_topics, |subscriptions| ControlResponse::Topics {
+            confirmed: subscriptions.confirmed(),
+            pending: subscriptions.pending(),
+        }).await,
+        ControlRequest::Reconnect => forward(state, Request::Reconnect),
+        ControlRequest::Flush => forward(state, Request::Flush),
+        ControlRequest::Shutdown => ControlResponse::Ok,
+    }
+}
+
+// The connection stops taking requests once it's closed
+fn forward(state: &ControlState, request: Request) -> ControlResponse {
+    // Requests about the connection are never dropped
+    match state.sender.push(request) {
+        Ok(_) => ControlResponse::Ok,
+        Err(_) => ControlResponse::Error { reason: "The connection is closed.".to_owned() },
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::thread;
+use log::{debug, error, warn};
+use zeroize::Zeroizing;
+
+use crate::config::read_secret_file;
+
+// Where libzmq sends authentication requests, fixed by the
+// ZAP specification
+const ZAP_ENDPOINT: &str = "inproc://zeromq.zap.01";
+const ZAP_VERSION: &[u8] = b"1.0";
+const ZAP_DOMAIN: &str = "herd";
+const KEY_LENGTH: usize = 32;
+
+// What the outbound, inbound and control sockets need to only
+// accept clients whose public key is allowed
+pub struct CurveServer {
+    secret_key: Zeroizing<Vec<u8>>,
+    allowed_keys: HashSet<Vec<u8>>,
+}
+
+impl CurveServer {
+    // Reads the daemon's secret key and the public keys of the
+    // clients allowed to connect, from the config file and from
+    // a file holding one key per line.
+    pub fn load(
+        secret_key_file: &str,
+        allowed_keys: &[String],
+        allowed_keys_file: Option<&str>,
+    ) -> Result<CurveServer, String> {
+        if zmq::has("curve") != Some(true) {
+            return Err("CURVE was configured but ZeroMQ was built without it.".to_owned());
+        }
+
+        let secret_key = read_secret_file(secret_key_file, "CURVE secret key")?;
+        let secret_key = match zmq::z85_decode(&secret_key) {
+            Ok(ref k) if k.len() == KEY_LENGTH => Zeroizing::new(k.clone()),
+            _ => return Err(format!("CURVE secret key file {} doesn't hold a Z85 encoded key.", secret_key_file)),
+        };
+
+        let mut keys = allowed_keys.to_vec();
+        if let Some(path) = allowed_keys_file {
+            let contents = match fs::read_to_string(path) {
+                Ok(c) => c,
+                Err(e) => return Err(format!("Error reading CURVE allowed keys file {}: {}", path, e)),
+            };
+            keys.extend(contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_owned));
+        }
+        if keys.is_empty() {
+            return Err("CURVE was configured without any allowed client keys.".to_owned());
+        }
+
+        let mut allowed_keys = HashSet::new();
+        for key in keys {
+            allowed_keys.insert(parse_public_key(&key)?);
+        }
+        Ok(CurveServer { secret_key, allowed_keys })
+    }
+
+    // Makes the socket a CURVE server, must be called before
+    // the socket is bound
+    pub(crate) fn apply(&self, socket: &zmq::Socket, name: &str) -> Result<(), String> {
+        socket.set_curve_server(true)
+            .and_then(|_| socket.set_curve_secretkey(&self.secret_key))
+            .and_then(|_| socket.set_zap_domain(ZAP_DOMAIN))
+            .map_err(|e| format!("Error enabling CURVE on the {} socket: {}", name, e))
+    }
+
+    // Answers the authentication requests of the sockets of the
+    // context, which has to outlive the daemon's sockets
+    pub(crate) fn authenticate(&self, context: &zmq::Context) -> Result<(), String> {
+        let handler = match context.socket(zmq::REP) {
+            Ok(s) => s,
+            Err(e) => return Err(format!("Error creating the authentication socket: {}", e)),
+        };
+        if let Err(e) = handler.bind(ZAP_ENDPOINT) {
+            return Err(format!("Error binding the authentication socket to {}: {}", ZAP_ENDPOINT, e));
+        }
+
+        let allowed_keys = self.allowed_keys.clone();
+        // Only stops once the context is terminated, without it
+        // no client could connect anymore
+        thread::spawn(move || loop {
+            let request = match handler.recv_multipart(0) {
+                Ok(r) => r,
+                Err(zmq::Error::ETERM) => break,
+                Err(e) => {
+                    error!("Error receiving authentication request: {}", e);
+                    continue;
+                },
+            };
+            let reply = reply(&request, &allowed_keys);
+            match handler.send_multipart(reply.iter(), 0) {
+                Ok(()) => (),
+                Err(zmq::Error::ETERM) => break,
+                Err(e) => error!("Error answering authentication request: {}", e),
+            }
+        });
+        Ok(())
+    }
+}
+
+// Requests are version, request id, domain, address, routing
+// id, mechanism and the client's public key
+fn reply(request: &[Vec<u8>], allowed_keys: &HashSet<Vec<u8>>) -> Vec<Vec<u8>> {
+    let request_id = request.get(1).cloned().unwrap_or_default();
+    let address = request.get(3).map(|a| String::from_utf8_lossy(a).into_owned()).unwrap_or_default();
+    let (status, text, user_id) = match (request.first(), request.get(5), request.get(6)) {
+        (Some(version), _, _) if version.as_slice() != ZAP_VERSION => {
+            warn!("Rejected connection from {}, unsupported authentication version.", address);
+            ("400", "Unsupported version", Vec::new())
+        },
+        (_, Some(mechanism), Some(key)) if mechanism.as_slice() == b"CURVE" => {
+            let encoded = zmq::z85_encode(key).unwrap_or_default();
+            if allowed_keys.contains(key) {
+                debug!("Accepted connection from {} with CURVE key {}.", address, encoded);
+                ("200", "OK", encoded.into_bytes())
+            } else {
+                warn!("Rejected connection from {} with CURVE key {}, the key isn't allowed.", address, encoded);
+                ("400", "Key not allowed", Vec::new())
+            }
+        },
+        _ => {
+            warn!("Rejected connection from {} without CURVE.", address);
+            ("400", "CURVE required", Vec::new())
+        },
+    };
+    vec![
+        ZAP_VERSION.to_vec(),
+        request_id,
+        status.as_bytes().to_vec(),
+        text.as_bytes().to_vec(),
+        user_id,
+        Vec::new(),
+    ]
+}
+
+pub fn parse_public_key(key: &str) -> Result<Vec<u8>, String> {
+    match zmq::z85_decode(key) {
+        Ok(k) if k.len() == KEY_LENGTH => Ok(k),
+        _ => Err(format!("Invalid CURVE public key {:?}, expected 40 Z85 characters.", key)),
+    }
+}
+
+// Writes the secret key of a new keypair to a file only its
+// owner can read and returns the public key to give clients.
+// An existing file is never overwritten.
+pub fn generate_keypair(secret_key_file: &str) -> Result<String, String> {
+    let keypair = match zmq::CurveKeyPair::new() {
+        Ok(k) => k,
+        Err(e) => return Err(format!("Error generating CURVE keypair: {}", e)),
+    };
+    let public_key = zmq::z85_encode(&keypair.public_key).unwrap();
+    let secret_key = Zeroizing::new(zmq::z85_encode(&keypair.secret_key).unwrap());
+
+    let result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(secret_key_file)
+        .and_then(|mut file| writeln!(file, "{}", *secret_key));
+    match result {
+        Ok(()) => Ok(public_key),
+        Err(e) => Err(format!("Error writing CURVE secret key file {}: {}", secret_key_file, e)),
+    }
+}
@@ -0,0 +1,607 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use log::{error, LevelFilter};
+use serde_json::Value;
+use tokio::runtime::Builder;
+use tokio::task::LocalSet;
+use zeroize::Zeroizing;
+
+use crate::async_socket::AsyncSocket;
+use crate::channel::{Overflow, DEFAULT_CHANNEL_CAPACITY};
+use crate::client::Subscription;
+use crate::config::{
+    loopback_endpoint,
+    parse_server_url,
+    DEFAULT_OUTBOUND_PORT,
+    DEFAULT_INBOUND_PORT,
+    DEFAULT_CONTROL_PORT,
+    DEFAULT_SERVER_URL,
+    DEFAULT_SHUTDOWN_TIMEOUT_MILLIS,
+    DEFAULT_STATE_DIR,
+};
+use crate::connection::{ConnectionStatus, Reload, Settings};
+use crate::control::ControlState;
+use crate::curve::CurveServer;
+use crate::endpoints::{bind, SocketPermissions};
+use crate::identity::IdentitySource;
+use crate::inflight::DEFAULT_MAX_INFLIGHT;
+use crate::ipc::{InboundFraming, Publisher};
+use crate::logging::LogFormat;
+use crate::models::{ClientInformation, ClientMessage, InboundMessage, Qos, Request, Status};
+use crate::queue::{DiskQueue, FsyncPolicy, DEFAULT_QUEUE_MAX_BYTES};
+use crate::retry::{
+    RetryPolicy,
+    RetryStrategy,
+    DEFAULT_RETRY_BASE_MILLIS,
+    DEFAULT_RETRY_MAX_MILLIS,
+    DEFAULT_MAX_RETRIES,
+};
+use crate::subscriptions::Subscriptions;
+use crate::tls::TlsOptions;
+
+// Endpoint the outbound socket is bound to within the process
+const INTERNAL_ENDPOINT: &str = "inproc://herd-control";
+// Endpoint the inbound socket is bound to within the process,
+// for subscriptions made through a DaemonHandle
+const INTERNAL_INBOUND_ENDPOINT: &str = "inproc://herd-inbound";
+// How long a DaemonHandle waits for the daemon to take a
+// message before giving up
+const SEND_TIMEOUT_MILLIS: i32 = 5000;
+
+// Builds the settings the daemon is reloaded with, only the
+// credentials, server url, TLS options, retry policy, shutdown
+// timeout and logging are taken from it
+pub type ReloadDaemon = Arc<dyn Fn() -> Result<Daemon, String> + Send + Sync>;
+
+// How to run a daemon. Everything but the credentials has the
+// same default as the command line.
+pub struct Daemon {
+    account_id: String,
+    api_key: Zeroizing<String>,
+    device_type_id: String,
+    device_id: Option<String>,
+    identity: IdentitySource,
+    interface: Option<String>,
+    server_url: String,
+    tls_options: TlsOptions,
+    retry_policy: RetryPolicy,
+    shutdown_timeout: Duration,
+    outbound_endpoint: String,
+    inbound_endpoint: String,
+    control_endpoint: String,
+    inbound_framing: InboundFraming,
+    socket_permissions: SocketPermissions,
+    curve: Option<CurveServer>,
+    state_dir: PathBuf,
+    queue_max_bytes: u64,
+    queue_max_age: Option<Duration>,
+    queue_fsync: FsyncPolicy,
+    max_inflight: usize,
+    channel_capacity: usize,
+    channel_overflow: Overflow,
+    ephemeral: bool,
+    handle_signals: bool,
+    logging: Option<(LevelFilter, LogFormat)>,
+    reload: Option<ReloadDaemon>,
+}
+
+impl Daemon {
+    pub fn new(account_id: &str, api_key: Zeroizing<String>, device_type_id: &str) -> Daemon {
+        Daemon {
+            account_id: account_id.to_owned(),
+            api_key,
+            device_type_id: device_type_id.to_owned(),
+            device_id: None,
+            identity: IdentitySource::Mac,
+            interface: None,
+            server_url: DEFAULT_SERVER_URL.to_owned(),
+            tls_options: TlsOptions {
+                ca_file: None,
+                system_roots: true,
+                client_cert: None,
+                client_key: None,
+            },
+            // The defaults are always valid
+            retry_policy: RetryPolicy::new(
+                RetryStrategy::Fixed,
+                DEFAULT_RETRY_BASE_MILLIS,
+                DEFAULT_RETRY_MAX_MILLIS,
+                DEFAULT_MAX_RETRIES,
+            ).unwrap(),
+            shutdown_timeout: Duration::from_millis(DEFAULT_SHUTDOWN_TIMEOUT_MILLIS),
+            outbound_endpoint: loopback_endpoint(DEFAULT_OUTBOUND_PORT),
+            inbound_endpoint: loopback_endpoint(DEFAULT_INBOUND_PORT),
+            control_endpoint: loopback_endpoint(DEFAULT_CONTROL_PORT),
+            inbound_framing: InboundFraming::Single,
+            socket_permissions: SocketPermissions::default(),
+            curve: None,
+            state_dir: PathBuf::from(DEFAULT_STATE_DIR),
+            queue_max_bytes: DEFAULT_QUEUE_MAX_BYTES,
+            queue_max_age: None,
+            queue_fsync: FsyncPolicy::Always,
+            max_inflight: DEFAULT_MAX_INFLIGHT,
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            channel_overflow: Overflow::Block,
+            ephemeral: false,
+            handle_signals: false,
+            logging: None,
+            reload: None,
+        }
+    }
+
+    // Used as is instead of being computed from identity
+    pub fn device_id(mut self, device_id: &str) -> Daemon {
+        self.device_id = Some(device_id.to_owned());
+        self
+    }
+
+    pub fn identity(mut self, identity: IdentitySource, interface: Option<&str>) -> Daemon {
+        self.identity = identity;
+        self.interface = interface.map(str::to_owned);
+        self
+    }
+
+    pub fn server_url(mut self, server_url: &str) -> Daemon {
+        self.server_url = server_url.to_owned();
+        self
+    }
+
+    pub fn tls(mut self, tls_options: TlsOptions) -> Daemon {
+        self.tls_options = tls_options;
+        self
+    }
+
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Daemon {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn shutdown_timeout(mut self, shutdown_timeout: Duration) -> Daemon {
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    pub fn endpoints(mut self, outbound: &str, inbound: &str, control: &str) -> Daemon {
+        self.outbound_endpoint = outbound.to_owned();
+        self.inbound_endpoint = inbound.to_owned();
+        self.control_endpoint = control.to_owned();
+        self
+    }
+
+    pub fn inbound_framing(mut self, inbound_framing: InboundFraming) -> Daemon {
+        self.inbound_framing = inbound_framing;
+        self
+    }
+
+    // Applied to the socket files of ipc:// endpoints
+    pub fn socket_permissions(mut self, socket_permissions: SocketPermissions) -> Daemon {
+        self.socket_permissions = socket_permissions;
+        self
+    }
+
+    pub fn curve(mut self, curve: CurveServer) -> Daemon {
+        self.curve = Some(curve);
+        self
+    }
+
+    // Where the queue, the registered topics and a random
+    // device id are kept
+    pub fn state_dir(mut self, state_dir: PathBuf) -> Daemon {
+        self.state_dir = state_dir;
+        self
+    }
+
+    pub fn queue_max_bytes(mut self, queue_max_bytes: u64) -> Daemon {
+        self.queue_max_bytes = queue_max_bytes;
+        self
+    }
+
+    // None keeps queued events until they're sent
+    pub fn queue_max_age(mut self, queue_max_age: Option<Duration>) -> Daemon {
+        self.queue_max_age = queue_max_age;
+        self
+    }
+
+    pub fn queue_fsync(mut self, queue_fsync: FsyncPolicy) -> Daemon {
+        self.queue_fsync = queue_fsync;
+        self
+    }
+
+    pub fn max_inflight(mut self, max_inflight: usize) -> Daemon {
+        self.max_inflight = max_inflight;
+        self
+    }
+
+    // How many messages can wait between local clients and the
+    // connection, in each direction. Data goes to the queue
+    // instead and is bounded by queue_max_bytes.
+    pub fn channel_capacity(mut self, channel_capacity: usize) -> Daemon {
+        self.channel_capacity = channel_capacity;
+        self
+    }
+
+    // What happens to messages from local clients once the
+    // outbound queue or the channel is full. Messages to local
+    // clients always drop the oldest.
+    pub fn channel_overflow(mut self, channel_overflow: Overflow) -> Daemon {
+        self.channel_overflow = channel_overflow;
+        self
+    }
+
+    // Starts without any registrations and doesn't keep them
+    pub fn ephemeral(mut self, ephemeral: bool) -> Daemon {
+        self.ephemeral = ephemeral;
+        self
+    }
+
+    // Shuts down on SIGTERM and SIGINT and reloads on SIGHUP.
+    // Off by default, signals belong to the application.
+    pub fn handle_signals(mut self, handle_signals: bool) -> Daemon {
+        self.handle_signals = handle_signals;
+        self
+    }
+
+    // Applied to the installed logger once the rest of the
+    // settings are built, when the daemon is opened and on every
+    // reload. A reload that fails keeps the current logging.
+    pub fn logging(mut self, level: LevelFilter, format: LogFormat) -> Daemon {
+        self.logging = Some((level, format));
+        self
+    }
+
+    // Without it reloading keeps the settings the daemon was
+    // started with
+    pub fn reload(mut self, reload: ReloadDaemon) -> Daemon {
+        self.reload = Some(reload);
+        self
+    }
+
+    // Reads everything the daemon needs from disk, for callers
+    // that drop privileges before binding the sockets
+    pub fn open(self) -> Result<OpenDaemon, String> {
+        if self.outbound_endpoint == self.inbound_endpoint
+            || self.outbound_endpoint == self.control_endpoint
+            || self.inbound_endpoint == self.control_endpoint
+        {
+            return Err(format!(
+                "The outbound, inbound and control endpoints must differ, they are {}, {} and {}.",
+                self.outbound_endpoint,
+                self.inbound_endpoint,
+                self.control_endpoint
+            ));
+        }
+        if self.channel_capacity == 0 {
+            return Err("channel_capacity must be greater than zero.".to_owned());
+        }
+
+        let device_id = match &self.device_id {
+            Some(device_id) => device_id.clone(),
+            None => crate::identity::device_id(self.identity, self.interface.as_deref(), &self.state_dir)?,
+        };
+        let settings = self.settings(&device_id)?;
+        self.configure_logging();
+
+        let reload: Reload = match self.reload.clone() {
+            Some(reload) => Arc::new(move || {
+                let daemon = reload()?;
+                let settings = daemon.settings(&device_id)?;
+                daemon.configure_logging();
+                Ok(settings)
+            }),
+            None => {
+                let settings = settings.clone();
+                Arc::new(move || Ok(settings.clone()))
+            },
+        };
+
+        let queue = DiskQueue::open(
+            &self.state_dir,
+            self.queue_max_bytes,
+            self.queue_max_age,
+            self.queue_fsync,
+            self.max_inflight,
+        )?;
+
+        let subscriptions = if self.ephemeral {
+            Subscriptions::new()
+        } else {
+            Subscriptions::open(&self.state_dir)?
+        };
+
+        Ok(OpenDaemon {
+            settings,
+            reload,
+            queue,
+            subscriptions,
+            daemon: self,
+        })
+    }
+
+    pub fn start(self) -> Result<DaemonHandle, String> {
+        self.open()?.start()
+    }
+
+    fn configure_logging(&self) {
+        if let Some((level, format)) = self.logging {
+            crate::logging::configure(level, format);
+        }
+    }
+
+    // What the connection is made with, the device id is used
+    // unless one was set
+    fn settings(&self, device_id: &str) -> Result<Settings, String> {
+        let server_url = parse_server_url(&self.server_url)?;
+        let tls_connector = if server_url.scheme() == "wss" {
+            Some(self.tls_options.build()?)
+        } else if !self.tls_options.is_default() {
+            return Err(format!("TLS options were given but the server url {} does not use wss.", server_url));
+        } else {
+            None
+        };
+
+        Ok(Settings {
+            client_information: ClientInformation::new(
+                self.device_id.as_deref().unwrap_or(device_id),
+                &self.device_type_id,
+                &self.account_id,
+                self.api_key.clone(),
+                server_url,
+                tls_connector,
+                self.tls_options.clone(),
+            ),
+            retry_policy: self.retry_policy.clone(),
+            shutdown_timeout: self.shutdown_timeout,
+        })
+    }
+}
+
+// A daemon whose state was read but that isn't running yet
+pub struct OpenDaemon {
+    settings: Settings,
+    reload: Reload,
+    queue: DiskQueue,
+    subscriptions: Subscriptions,
+    daemon: Daemon,
+}
+
+impl OpenDaemon {
+    // Binds the sockets and connects to the server
+    pub fn start(self) -> Result<DaemonHandle, String> {
+        let OpenDaemon { settings, reload, queue, subscriptions, daemon } = self;
+        let permissions = &daemon.socket_permissions;
+
+        let context = zmq::Context::new();
+        if let Some(curve) = &daemon.curve {
+            curve.authenticate(&context)?;
+        }
+
+        // For messages that come into the websocket, this is a channel
+        // to comunicate with the process outside
+        let inbound_socket = socket(&context, zmq::PUB, "inbound")?;
+        if let Some(curve) = &daemon.curve {
+            curve.apply(&inbound_socket, "inbound")?;
+        }
+        bind(&inbound_socket, &daemon.inbound_endpoint, "inbound", permissions)?;
+        bind(&inbound_socket, INTERNAL_INBOUND_ENDPOINT, "inbound", permissions)?;
+
+        // Messages from local clients to be sent to the server. Also
+        // bound in process so the daemon can reach it whatever the
+        // configured endpoint is, in process connections skip CURVE.
+        let outbound_socket = socket(&context, zmq::PULL, "outbound")?;
+        if let Some(curve) = &daemon.curve {
+            curve.apply(&outbound_socket, "outbound")?;
+        }
+        bind(&outbound_socket, &daemon.outbound_endpoint, "outbound", permissions)?;
+        bind(&outbound_socket, INTERNAL_ENDPOINT, "outbound", permissions)?;
+
+        // Requests about the daemon itself, answered right away
+        let control_socket = socket(&context, zmq::REP, "control")?;
+        if let Some(curve) = &daemon.curve {
+            curve.apply(&control_socket, "control")?;
+        }
+        bind(&control_socket, &daemon.control_endpoint, "control", permissions)?;
+
+        // This is a PUSH socket such that the websocket thread
+        // can tell the thread handles incoming messages
+        // from the client to close
+        let ipc_socket = socket(&context, zmq::PUSH, "internal")?;
+        if let Err(e) = ipc_socket.connect(INTERNAL_ENDPOINT) {
+            return Err(format!("Error connecting the internal socket to {}: {}", INTERNAL_ENDPOINT, e));
+        }
+        // Nothing sent here matters once the daemon is exiting
+        if let Err(e) = ipc_socket.set_linger(0) {
+            return Err(format!("Error configuring the internal socket: {}", e));
+        }
+        let ipc_socket = Arc::new(Mutex::new(ipc_socket));
+        if daemon.handle_signals {
+            if let Err(e) = crate::signals::initialize(ipc_socket.clone()) {
+                error!("{}", e);
+            }
+        }
+
+        // Messages sent through the handle, they go through the
+        // same checks as those of local clients
+        let client_socket = socket(&context, zmq::PUSH, "client")?;
+        let connected = client_socket.connect(INTERNAL_ENDPOINT)
+            .and_then(|_| client_socket.set_sndtimeo(SEND_TIMEOUT_MILLIS))
+            .and_then(|_| client_socket.set_linger(0));
+        if let Err(e) = connected {
+            return Err(format!("Error connecting the client socket to {}: {}", INTERNAL_ENDPOINT, e));
+        }
+
+        // Registered topics, resent whenever the connection restarts
+        let registered_topics = Arc::new(Mutex::new(subscriptions));
+
+        // Outbound events waiting to be sent to the server
+        let queue = Arc::new(Mutex::new(queue));
+
+        // Every loop runs as a task on this one thread, waiting
+        // on the sockets instead of blocking on them
+        let runtime = match Builder::new_current_thread().enable_all().build() {
+            Ok(r) => r,
+            Err(e) => return Err(format!("Error starting the runtime: {}", e)),
+        };
+        let (outbound_socket, control_socket) = {
+            let _guard = runtime.enter();
+            (async_socket(outbound_socket, "outbound")?, async_socket(control_socket, "control")?)
+        };
+
+        // DEFINITIONS
+        // outbound_: data and structures supporting data
+        // moving from inside the system to the external server
+        // inbound_: data and structures supporting data
+        // moving from data received from the servers meant
+        // for internal consumption
+        let (outbound_sender, outbound_receiver) = crate::channel::channel::<Request>(
+            daemon.channel_capacity,
+            daemon.channel_overflow,
+        );
+        // Publishing never waits on subscribers either, so the
+        // oldest messages make room for new ones
+        let (inbound_sender, inbound_receiver) = crate::channel::channel::<InboundMessage>(
+            daemon.channel_capacity,
+            Overflow::DropOldest,
+        );
+
+        // What the control socket reports, kept up to date by
+        // the connection
+        let status = Arc::new(Mutex::new(ConnectionStatus::new(&settings)));
+
+        let state = ControlState {
+            sender: outbound_sender.clone(),
+            inbound_counters: inbound_sender.counters(),
+            ipc_socket,
+            status: status.clone(),
+            registered_topics: registered_topics.clone(),
+            queue: queue.clone(),
+            started: Instant::now(),
+        };
+
+        let publisher = Publisher::new(inbound_socket, daemon.inbound_framing);
+        let control_state = state.clone();
+        let thread = thread::spawn(move || {
+            let tasks = LocalSet::new();
+            tasks.block_on(&runtime, async move {
+                let (outbound_task, inbound_task) = crate::ipc::initialize(
+                    outbound_sender,
+                    inbound_receiver,
+                    inbound_sender.clone(),
+                    outbound_socket,
+                    publisher,
+                    registered_topics.clone(),
+                    queue.clone(),
+                );
+                let websocket_task = crate::connection::initialize(
+                    settings,
+                    reload,
+                    outbound_receiver,
+                    inbound_sender,
+                    registered_topics,
+                    queue,
+                    status,
+                );
+                let control_task = crate::control::initialize(control_socket, control_state);
+
+                if let Err(e) = websocket_task.await {
+                    error!("Error in the websocket task: {}", e);
+                }
+                // The connection can give up on its own after
+                // running out of retries, nothing is read from
+                // local clients after that
+                outbound_task.abort();
+                control_task.abort();
+                // Publishes the last messages, ending with Close
+                if let Err(e) = inbound_task.await {
+                    error!("Error in the inbound task: {}", e);
+                }
+            });
+        });
+
+        Ok(DaemonHandle {
+            context,
+            client_socket: Mutex::new(client_socket),
+            state,
+            thread,
+            endpoints: vec![daemon.outbound_endpoint, daemon.inbound_endpoint, daemon.control_endpoint],
+            inbound_framing: daemon.inbound_framing,
+        })
+    }
+}
+
+fn socket(context: &zmq::Context, socket_type: zmq::SocketType, name: &str) -> Result<zmq::Socket, String> {
+    context.socket(socket_type)
+        .map_err(|e| format!("Error creating the {} socket: {}", name, e))
+}
+
+fn async_socket(socket: zmq::Socket, name: &str) -> Result<AsyncSocket, String> {
+    AsyncSocket::new(socket)
+        .map_err(|e| format!("Error watching the {} socket: {}", name, e))
+}
+
+// A running daemon. Acks, nacks and everything else the daemon
+// publishes are received through subscribe.
+pub struct DaemonHandle {
+    context: zmq::Context,
+    client_socket: Mutex<zmq::Socket>,
+    state: ControlState,
+    // Runs the websocket, outbound, inbound and control tasks
+    thread: JoinHandle<()>,
+    // Whose socket files are removed on exit
+    endpoints: Vec<String>,
+    inbound_framing: InboundFraming,
+}
+
+impl DaemonHandle {
+    pub fn send(&self, topics: Vec<String>, data: Value, qos: Qos, id: Option<String>) -> Result<(), String> {
+        self.push(&ClientMessage::Data { topics, data, qos, id })
+    }
+
+    pub fn register(&self, topics: Vec<String>, id: Option<String>) -> Result<(), String> {
+        self.push(&ClientMessage::Register { topics, id })
+    }
+
+    pub fn unregister(&self, topics: Vec<String>, id: Option<String>) -> Result<(), String> {
+        self.push(&ClientMessage::Unregister { topics, id })
+    }
+
+    // Messages published before the subscription is made are
+    // not received
+    pub fn subscribe(&self, topics: &[String]) -> Result<Subscription, String> {
+        Subscription::connect(&self.context, INTERNAL_INBOUND_ENDPOINT, self.inbound_framing, topics)
+    }
+
+    pub fn status(&self) -> Status {
+        crate::control::status(&self.state)
+    }
+
+    // Closes the connection the same way a Close message does
+    // and waits for the daemon to exit
+    pub fn shutdown(self) {
+        if let Err(e) = crate::signals::request_close(&self.state.ipc_socket) {
+            error!("Error requesting close: {}", e);
+        }
+        self.wait();
+    }
+
+    // Waits for the daemon to exit on its own, after a Close
+    // message, a signal or running out of retries
+    pub fn wait(self) {
+        if self.thread.join().is_err() {
+            error!("The daemon thread panicked.");
+        }
+        for endpoint in &self.endpoints {
+            crate::endpoints::remove(endpoint);
+        }
+    }
+
+    fn push(&self, message: &ClientMessage) -> Result<(), String> {
+        let message = serde_json::to_string(message).unwrap();
+        match self.client_socket.lock().unwrap().send(message.as_bytes(), 0) {
+            Ok(()) => Ok(()),
+            Err(zmq::Error::EAGAIN) => Err("The daemon isn't taking messages.".to_owned()),
+            Err(e) => Err(format!("Error sending message: {}", e)),
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::str::FromStr;
+use log::{debug, warn};
+use serde::Deserialize;
+
+const IPC_SCHEME: &str = "ipc://";
+
+// Permission bits for socket files, written in octal
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct FileMode(pub u32);
+
+impl FromStr for FileMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FileMode, String> {
+        match u32::from_str_radix(s, 8) {
+            Ok(mode) if mode <= 0o777 => Ok(FileMode(mode)),
+            _ => Err(format!("Invalid mode {:?}, expected octal permissions like 660.", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for FileMode {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<FileMode, String> {
+        s.parse()
+    }
+}
+
+// Who may use the files of ipc:// endpoints. Anything not set
+// is left as the daemon created it.
+#[derive(Debug, Clone, Default)]
+pub struct SocketPermissions {
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    pub mode: Option<FileMode>,
+}
+
+// Binds the socket, taking care of the socket file of ipc://
+// endpoints: its directory is created, a file left behind by a
+// daemon that is no longer running is removed, and the
+// permissions are applied once bound.
+pub(crate) fn bind(
+    socket: &zmq::Socket,
+    endpoint: &str,
+    name: &str,
+    permissions: &SocketPermissions,
+) -> Result<(), String> {
+    let path = ipc_path(endpoint);
+    if let Some(path) = path {
+        prepare(path).map_err(|e| format!("Error preparing the {} socket file {}: {}", name, path.display(), e))?;
+    }
+
+    if let Err(e) = socket.bind(endpoint) {
+        return Err(format!("Error binding the {} socket to {}: {} (errno {}).", name, endpoint, e, e.to_raw()));
+    }
+
+    match path {
+        Some(path) => apply(path, permissions)
+            .map_err(|e| format!("Error setting permissions of the {} socket file {}: {}", name, path.display(), e)),
+        None => Ok(()),
+    }
+}
+
+// Removes the socket file of an ipc:// endpoint, once the daemon
+// is done with it
+pub(crate) fn remove(endpoint: &str) {
+    if let Some(path) = ipc_path(endpoint) {
+        if let Err(e) = fs::remove_file(path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Error removing socket file {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+fn ipc_path(endpoint: &str) -> Option<&Path> {
+    endpoint.strip_prefix(IPC_SCHEME).map(Path::new)
+}
+
+fn prepare(path: &Path) -> io::Result<()> {
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "a file that isn't a socket is in the way"));
+    }
+
+    // Nobody listening means the daemon that made it is gone
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AddrInUse, "another process is listening on it"));
+    }
+    debug!("Removing stale socket file {}.", path.display());
+    fs::remove_file(path)
+}
+
+fn apply(path: &Path, permissions: &SocketPermissions) -> io::Result<()> {
+    if permissions.owner.is_some() || permissions.group.is_some() {
+        let uid = match &permissions.owner {
+            Some(owner) => user_id(owner)?,
+            // chown leaves ids of -1 unchanged
+            None => libc::uid_t::MAX,
+        };
+        let gid = match &permissions.group {
+            Some(group) => group_id(group)?,
+            None => libc::gid_t::MAX,
+        };
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        if unsafe { libc::chown(c_path.as_ptr(), uid, gid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    if let Some(FileMode(mode)) = permissions.mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+fn user_id(name: &str) -> io::Result<libc::uid_t> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let c_name = CString::new(name)?;
+    let entry = unsafe { libc::getpwnam(c_name.as_ptr()) };
+    if entry.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("unknown user {}", name)));
+    }
+    Ok(unsafe { (*entry).pw_uid })
+}
+
+fn group_id(name: &str) -> io::Result<libc::gid_t> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let c_name = CString::new(name)?;
+    let entry = unsafe { libc::getgrnam(c_name.as_ptr()) };
+    if entry.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("unknown group {}", name)));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
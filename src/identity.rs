@@ -0,0 +1,107 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use mac_address::{get_mac_address, mac_address_by_name};
+use serde::Deserialize;
+use uuid::Uuid;
+
+const DEVICE_ID_FILE: &str = "device_id";
+const MACHINE_ID_FILES: [&str; 2] = ["/etc/machine-id", "/var/lib/dbus/machine-id"];
+const DMI_UUID_FILE: &str = "/sys/class/dmi/id/product_uuid";
+
+// Where the device id is derived from
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentitySource {
+    // The MAC address of the first interface, or of the one
+    // given with --interface
+    Mac,
+    // The systemd machine id
+    MachineId,
+    // Generated once and kept in the state directory
+    Random,
+    // The product UUID set by the hardware vendor
+    DmiUuid,
+}
+
+impl FromStr for IdentitySource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<IdentitySource, String> {
+        match s {
+            "mac" => Ok(IdentitySource::Mac),
+            "machine_id" => Ok(IdentitySource::MachineId),
+            "random" => Ok(IdentitySource::Random),
+            "dmi_uuid" => Ok(IdentitySource::DmiUuid),
+            _ => Err(format!(
+                "Unknown identity source {:?}, expected mac, machine_id, random or dmi_uuid.",
+                s
+            )),
+        }
+    }
+}
+
+// Computes the id the device connects with. The same source
+// always gives the same id on the same device.
+pub fn device_id(source: IdentitySource, interface: Option<&str>, state_dir: &Path) -> Result<String, String> {
+    let seed = match source {
+        IdentitySource::Mac => mac_address(interface)?.to_vec(),
+        IdentitySource::MachineId => read_first(&MACHINE_ID_FILES, "machine id")?.into_bytes(),
+        IdentitySource::DmiUuid => read_first(&[DMI_UUID_FILE], "DMI product UUID")?.into_bytes(),
+        IdentitySource::Random => return random_device_id(state_dir),
+    };
+    Ok(format_device_id(Uuid::new_v5(&Uuid::NAMESPACE_DNS, &seed)))
+}
+
+fn mac_address(interface: Option<&str>) -> Result<[u8; 6], String> {
+    let address = match interface {
+        Some(name) => mac_address_by_name(name),
+        None => get_mac_address(),
+    };
+    match (address, interface) {
+        (Ok(Some(ma)), _) => Ok(ma.bytes()),
+        (Ok(None), Some(name)) => Err(format!("No MAC address found for interface {}, can't compute unique id.", name)),
+        (Ok(None), None) => Err("No MAC address found, can't compute unique id.".to_owned()),
+        (Err(e), _) => Err(format!("Error obtaining mac address, can't compute unique id. {}", e)),
+    }
+}
+
+fn read_first(paths: &[&str], name: &str) -> Result<String, String> {
+    for path in paths {
+        if let Ok(contents) = fs::read_to_string(path) {
+            let contents = contents.trim();
+            if !contents.is_empty() {
+                return Ok(contents.to_owned());
+            }
+        }
+    }
+    Err(format!("No {} found in {}, can't compute unique id.", name, paths.join(" or ")))
+}
+
+// Generated the first time and read back afterwards, so it
+// only changes if the state directory is wiped
+fn random_device_id(state_dir: &Path) -> Result<String, String> {
+    let path = state_dir.join(DEVICE_ID_FILE);
+    if let Ok(contents) = fs::read_to_string(&path) {
+        let device_id = contents.trim();
+        if !device_id.is_empty() {
+            return Ok(device_id.to_owned());
+        }
+    }
+
+    let device_id = format_device_id(Uuid::new_v4());
+    let temporary = path.with_extension("tmp");
+    let result = fs::create_dir_all(state_dir)
+        .and_then(|_| fs::write(&temporary, &device_id))
+        .and_then(|_| fs::rename(&temporary, &path));
+    match result {
+        Ok(()) => Ok(device_id),
+        Err(e) => Err(format!("Error writing device id {}: {}", path.display(), e)),
+    }
+}
+
+fn format_device_id(uuid: Uuid) -> String {
+    let mut buffer: [u8; 45] = Uuid::encode_buffer();
+    let uuid = uuid.to_simple().encode_lower(&mut buffer);
+    format!("dev_{}", uuid)
+}
@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::queue::QueueEntry;
+
+pub const DEFAULT_MAX_INFLIGHT: usize = 100;
+
+const INFLIGHT_FILE: &str = "inflight.json";
+
+// At least once events that have been sent but not yet
+// acknowledged by the server. They are kept on disk so a
+// restart of the daemon doesn't lose them, and are sent
+// again every time the connection is restarted.
+pub struct Inflight {
+    path: PathBuf,
+    entries: BTreeMap<u64, QueueEntry>,
+    max: usize,
+}
+
+impl Inflight {
+    pub fn open(directory: &Path, max: usize) -> Result<Inflight, String> {
+        let path = directory.join(INFLIGHT_FILE);
+        let entries = match fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str(&contents) {
+                Ok(e) => e,
+                Err(e) => return Err(format!("Error parsing {}: {}", path.display(), e)),
+            },
+            Err(_) => BTreeMap::new(),
+        };
+
+        Ok(Inflight {
+            path,
+            entries,
+            max,
+        })
+    }
+
+    // No more events should be sent until some are acknowledged
+    pub fn is_full(&self) -> bool {
+        self.entries.len() >= self.max
+    }
+
+    pub fn insert(&mut self, id: u64, entry: QueueEntry) -> Result<(), String> {
+        self.entries.insert(id, entry);
+        self.save()
+    }
+
+    // Returns None if the id wasn't waiting for an acknowledgement
+    pub fn remove(&mut self, id: u64) -> Result<Option<QueueEntry>, String> {
+        let entry = match self.entries.remove(&id) {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+        self.save()?;
+        Ok(Some(entry))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn max_id(&self) -> Option<u64> {
+        self.entries.keys().next_back().copied()
+    }
+
+    // Unacknowledged entries, oldest first
+    pub fn entries(&self) -> Vec<QueueEntry> {
+        self.entries.values().cloned().collect()
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let temporary = self.path.with_extension("tmp");
+        let contents = match serde_json::to_vec(&self.entries) {
+            Ok(c) => c,
+            Err(e) => return Err(format!("Error serializing inflight events: {}", e)),
+        };
+
+        let result = File::create(&temporary).and_then(|mut file| {
+            file.write_all(&contents)?;
+            file.sync_data()?;
+            fs::rename(&temporary, &self.path)
+        });
+        result.map_err(|e| format!("Error writing {}: {}", self.path.display(), e))
+    }
+}
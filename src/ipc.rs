@@ -1,21 +1,118 @@
-use std::sync::mpsc::{Sender, Receiver};
 use std::sync::{Arc, Mutex};
-use std::collections::HashSet;
-use std::thread;
-use std::thread::JoinHandle;
+use std::str::FromStr;
+use std::borrow::Cow;
 use std::time::SystemTime;
-use serde_json::{Result as SerdeResult};
-use zmq;
+use serde::Deserialize;
+use serde_json::{Value, Result as SerdeResult};
+use tokio::sync::Notify;
+use tokio::task::{self, JoinHandle};
+use log::{error, warn, info, trace};
 
-use crate::utils::maybe_error;
+use crate::async_socket::AsyncSocket;
+use crate::channel::{Counters, Overflow, Sender, Receiver};
+use crate::queue::{DiskQueue, Pushed, QueueEntry};
+use crate::subscriptions::Subscriptions;
+use crate::utils::{maybe_error, acknowledge, reject, blocking};
 
 use crate::models::{
+    AckStatus,
     ClientMessage,
     Event,
     Request,
     InboundMessage,
 };
 
+// Topics of messages published by the daemon itself start with
+// this, server topics never do
+pub const CONTROL_TOPIC_PREFIX: &str = "$herd/";
+
+// How messages are laid out on the inbound socket
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InboundFraming {
+    // One frame holding the JSON message
+    Single,
+    // A topic frame followed by the JSON message, so clients
+    // can subscribe to the topics they handle. Data is sent
+    // once per topic it was published to, everything else
+    // under a $herd/ topic.
+    Topic,
+}
+
+impl FromStr for InboundFraming {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<InboundFraming, String> {
+        match s {
+            "single" => Ok(InboundFraming::Single),
+            "topic" => Ok(InboundFraming::Topic),
+            _ => Err(format!("Unknown inbound framing {:?}, expected single or topic.", s)),
+        }
+    }
+}
+
+// The inbound socket and how to frame what's sent on it
+pub(crate) struct Publisher {
+    socket: zmq::Socket,
+    framing: InboundFraming,
+}
+
+impl Publisher {
+    pub fn new(socket: zmq::Socket, framing: InboundFraming) -> Publisher {
+        Publisher { socket, framing }
+    }
+
+    fn publish(&self, message: &InboundMessage) -> zmq::Result<()> {
+        let payload = payload(message);
+        match self.framing {
+            InboundFraming::Single => self.socket.send(payload.as_bytes(), 0),
+            InboundFraming::Topic => {
+                for topic in topics(message) {
+                    self.socket.send_multipart([topic.as_bytes(), payload.as_bytes()], 0)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+// What is sent on the inbound socket, data from the server
+// as it was received and everything else as JSON
+pub fn payload(message: &InboundMessage) -> Cow<'_, str> {
+    match message {
+        InboundMessage::Data(d) => Cow::Borrowed(d),
+        _ => Cow::Owned(serde_json::to_string(message).unwrap()),
+    }
+}
+
+// Data carries the topics it was published to in its message,
+// data without any is sent under $herd/data
+pub fn topics(message: &InboundMessage) -> Vec<String> {
+    let name = match message {
+        InboundMessage::Data(d) => {
+            let topics: Vec<String> = serde_json::from_str::<Value>(d)
+                .ok()
+                .and_then(|v| v.pointer("/message/topics").cloned())
+                .and_then(|t| serde_json::from_value(t).ok())
+                .unwrap_or_default();
+            if !topics.is_empty() {
+                return topics;
+            }
+            "data"
+        },
+        InboundMessage::Restart { .. } => "restart",
+        InboundMessage::Ack { .. } => "ack",
+        InboundMessage::Nack { .. } => "nack",
+        InboundMessage::Registrations { .. } => "registrations",
+        InboundMessage::Close => "close",
+    };
+    vec![format!("{}{}", CONTROL_TOPIC_PREFIX, name)]
+}
+
+fn reserved_topic(topics: &[String]) -> Option<&String> {
+    topics.iter().find(|t| t.starts_with(CONTROL_TOPIC_PREFIX))
+}
+
 struct CreatedAt {
     seconds_since_unix: u64,
     nano_seconds: u32,
@@ -38,114 +135,254 @@ fn get_time() -> Result<CreatedAt, &'static str> {
     )
 }
 
-// TODO: create new "receiver thread" (handles inbound connections
-// to be passed to client) with receiver channel. Would allow
-// for messages to be sent more easily for information concerning
-// connection status, retry logic, shutdown
-pub fn initialize<'a>(
+// Message ids start at the current time in microseconds so
+// they keep increasing across restarts of the daemon. Devices
+// without a real time clock start at 1970, so they also start
+// after every id still waiting in the queue.
+fn initial_message_id(max_queued_id: Option<u64>) -> u64 {
+    let now = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(t) => t.as_micros() as u64,
+        Err(_) => 0,
+    };
+    match max_queued_id {
+        Some(id) => now.max(id + 1),
+        None => now,
+    }
+}
+
+// Starts the tasks moving messages between local clients and
+// the connection, must be called from within a LocalSet
+pub(crate) fn initialize(
     sender: Sender<Request>,
-    receiver: Receiver<InboundMessage>,
-    outbound_port: &'a str,
-    context: zmq::Context,
-    inbound_socket: zmq::Socket,
-    registered_topics: Arc<Mutex<HashSet::<String>>>,
+    mut receiver: Receiver<InboundMessage>,
+    inbound_sender: Sender<InboundMessage>,
+    subscriber: AsyncSocket,
+    publisher: Publisher,
+    registered_topics: Arc<Mutex<Subscriptions>>,
+    queue: Arc<Mutex<DiskQueue>>,
 ) -> (JoinHandle<()>, JoinHandle<()>) {
-    let subscriber = context.socket(zmq::PULL).unwrap();
-    let outbound_tcp_port = format!("tcp://*:{}", outbound_port);
-    assert!(subscriber.bind(&outbound_tcp_port).is_ok());
-
-    // Sender thread: receives a message to be send over websocket
-    let sender_thread = thread::spawn(move || {
+    // Sender task: receives a message to be send over websocket
+    let sender_task = task::spawn_local(async move {
+        // Data that doesn't fit in the queue is handled the same
+        // way as messages that don't fit in the channel
+        let overflow = sender.overflow();
+        let counters = sender.counters();
+        let (max_queued_id, room) = blocking(&queue, |q| (q.max_message_id(), q.room())).await;
+        let mut next_message_id = initial_message_id(max_queued_id);
         loop {
-            let maybe_message = subscriber.recv_msg(0).unwrap();
+            let maybe_message = match subscriber.recv().await {
+                Ok(m) => m,
+                Err(e) => {
+                    error!("Error receiving message from a local client: {}", e);
+                    return;
+                },
+            };
             let time = match get_time() {
                 Ok(t) => t,
                 Err(e) => {
-                    println!("{:?}", e);
+                    error!("{}", e);
                     continue;
                 },
             };
             let message = match std::str::from_utf8(&maybe_message) {
                 Ok(m) => m,
-                Err(_) => continue,
+                Err(_) => {
+                    warn!("Ignoring message that isn't valid UTF-8.");
+                    continue;
+                },
+            };
+
+            trace!("Message received from a local client: {}", message);
+
+            let value: SerdeResult<Value> = serde_json::from_str(message);
+            let value = match value {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Error deserializing message: {}", e);
+                    continue;
+                }
             };
 
-            let client_message: SerdeResult<ClientMessage> = serde_json::from_str(message);
+            // Pulled out before parsing so invalid messages can
+            // still be rejected by id
+            let client_id = value.get("id").and_then(Value::as_str).map(str::to_owned);
+            let client_message: SerdeResult<ClientMessage> = serde_json::from_value(value);
 
             let client_message = match client_message {
                 Ok(d) => d,
                 Err(e) => {
-                    println!("Error deserializing data: {:?}", e);
+                    warn!("Error deserializing message: {}", e);
+                    reject(&inbound_sender, &client_id, format!("Invalid message: {}", e));
                     continue;
                 }
             };
 
+            // Validated before the message is acknowledged as
+            // parsed
+            let topics = match &client_message {
+                ClientMessage::Register { topics, .. } | ClientMessage::Data { topics, .. } => Some(topics),
+                _ => None,
+            };
+            if let Some(topic) = topics.and_then(|t| reserved_topic(t)) {
+                let reason = format!("Topic {} is reserved for the daemon.", topic);
+                reject(&inbound_sender, &client_id, reason);
+                continue;
+            }
+            acknowledge(&inbound_sender, &client_id, AckStatus::Parsed);
+
             match client_message {
                 ClientMessage::Close => {
-                    println!("Closing connection.");
-                    let _ = sender.send(Request::Close);
+                    info!("Close requested by a local client.");
+                    forward(&sender, &inbound_sender, Request::Close).await;
                     return;
                 },
                 ClientMessage::WebsocketClose => {
-                    println!("Websocket closed. Closing connection.");
+                    info!("Websocket closed, no longer reading from local clients.");
                     return;
                 },
-                ClientMessage::Register { topics } => {
-                    {
-                        let mut data = registered_topics.lock().unwrap();
-                        for topic in &topics {
-                            data.insert(topic.clone());
-                        }
-                    }
+                ClientMessage::Register { topics, id } => {
+                    let registering = topics.clone();
+                    maybe_error(blocking(&registered_topics, move |s| s.register(&registering)).await);
 
                     let event = Event::Register {
                         topics,
                     };
 
-                    let request_data = Request::Data(event);
-                    maybe_error(sender.send(request_data));
+                    forward(&sender, &inbound_sender, Request::Data(event, id)).await;
+
+                }
+                ClientMessage::Unregister { topics, id } => {
+                    let unregistering = topics.clone();
+                    maybe_error(blocking(&registered_topics, move |s| s.unregister(&unregistering)).await);
+
+                    let event = Event::Unregister {
+                        topics,
+                    };
 
+                    forward(&sender, &inbound_sender, Request::Data(event, id)).await;
+                }
+                ClientMessage::ListRegistrations { id } => {
+                    let registrations = blocking(&registered_topics, move |s| InboundMessage::Registrations {
+                        id,
+                        confirmed: s.confirmed(),
+                        pending: s.pending(),
+                    }).await;
+                    maybe_error(inbound_sender.push(registrations));
+                }
+                ClientMessage::Reload { id } => {
+                    forward(&sender, &inbound_sender, Request::Reload(id)).await;
                 }
-                ClientMessage::Data { topics, data } => {
+                ClientMessage::Data { topics, data, qos, id } => {
                     let event = Event::Message {
+                        id: next_message_id,
+                        qos,
                         seconds_since_unix: time.seconds_since_unix,
                         nano_seconds: time.nano_seconds,
                         topics,
                         data,
                     };
 
-                    let request_data = Request::Data(event);
-                    maybe_error(sender.send(request_data));
+                    next_message_id += 1;
+
+                    // Written to disk first so the event survives a
+                    // lost connection or a restart of the daemon
+                    let entry = QueueEntry::new(event, id.clone());
+                    match push(&queue, &room, entry, overflow).await {
+                        Ok(Pushed::Queued(evicted)) => {
+                            for entry in evicted {
+                                warn!("Outbound queue is full, dropping the oldest message.");
+                                dropped(&counters, &inbound_sender, &entry.client_id);
+                            }
+                            acknowledge(&inbound_sender, &id, AckStatus::Queued);
+                            forward(&sender, &inbound_sender, Request::Flush).await;
+                        },
+                        Ok(Pushed::Full) => {
+                            warn!("Outbound queue is full, dropping message.");
+                            dropped(&counters, &inbound_sender, &id);
+                        },
+                        Err(e) => {
+                            warn!("Dropping message: {}", e);
+                            reject(&inbound_sender, &id, e);
+                        },
+                    };
                 },
             };
+
+            // Everything runs on one thread, handing over after
+            // each message lets the connection and the publisher
+            // keep up with what's forwarded
+            task::yield_now().await;
         };
     });
 
-    let receiver_thread = thread::spawn(move || {
+    let receiver_task = task::spawn_local(async move {
         loop {
-            let message = match receiver.recv() {
-                Ok(m) => m,
-                Err(e) => {
-                    eprintln!("Error sending message: {:?}", e);
-                    continue;
-                }
-            };
-
-            let send_result = match message {
-                InboundMessage::Data(d) => inbound_socket.send(d.as_bytes(), 0),
-                InboundMessage::Restart =>
-                    inbound_socket.send(serde_json::to_string(&InboundMessage::Restart).unwrap().as_bytes(), 0),
-                InboundMessage::Close => {
-                    let _ = inbound_socket.send(serde_json::to_string(&InboundMessage::Close).unwrap().as_bytes(), 0);
+            // The connection always sends Close before it's gone
+            let message = match receiver.recv().await {
+                Some(m) => m,
+                None => {
+                    error!("Error receiving inbound message, the connection is gone.");
                     return;
                 },
             };
-            match send_result {
+
+            if let InboundMessage::Close = message {
+                let _ = publisher.publish(&message);
+                return;
+            }
+            match publisher.publish(&message) {
                 Ok(_) => (),
-                Err(e) => eprintln!("Error sending inbound message: {:?}", e),
+                Err(e) => error!("Error publishing inbound message: {:?}", e),
             }
         }
     });
 
-    return (sender_thread, receiver_thread);
-}
\ No newline at end of file
+    (sender_task, receiver_task)
+}
+
+// Sends a request to the connection, waiting for room if the
+// channel blocks when full. Whoever's message was dropped
+// instead is told so.
+async fn forward(sender: &Sender<Request>, inbound_sender: &Sender<InboundMessage>, request: Request) {
+    let dropped = match sender.send(request).await {
+        Ok(Some(d)) => d,
+        Ok(None) => return,
+        Err(e) => {
+            error!("Error forwarding message to the connection: {}", e);
+            return;
+        },
+    };
+
+    warn!("Outbound channel is full, dropping message.");
+    let reason = "Dropped, the daemon is receiving messages faster than it can handle them.".to_owned();
+    // Only events are ever dropped
+    if let Request::Data(_, id) = dropped {
+        reject(inbound_sender, &id, reason);
+    }
+}
+
+// Writes the entry to the outbound queue. Under Overflow::Block
+// a full queue holds up reading from local clients until the
+// connection makes room.
+async fn push(
+    queue: &Arc<Mutex<DiskQueue>>,
+    room: &Notify,
+    entry: QueueEntry,
+    overflow: Overflow,
+) -> Result<Pushed, String> {
+    loop {
+        let pushing = entry.clone();
+        match blocking(queue, move |q| q.push(&pushing, overflow)).await? {
+            Pushed::Full if overflow == Overflow::Block => room.notified().await,
+            pushed => return Ok(pushed),
+        }
+    }
+}
+
+// Counts a message that didn't fit in the outbound queue and
+// tells whoever sent it
+fn dropped(counters: &Counters, inbound_sender: &Sender<InboundMessage>, id: &Option<String>) {
+    counters.add_dropped();
+    let reason = "Dropped, the outbound queue is full.".to_owned();
+    reject(inbound_sender, id, reason);
+}
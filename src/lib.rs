@@ -0,0 +1,29 @@
+// The daemon as a library, for applications that run it in
+// process instead of next to them. The herd-daemon binary is
+// the command line on top of it.
+
+mod async_socket;
+mod channel;
+pub mod client;
+pub mod config;
+mod connection;
+mod control;
+pub mod curve;
+mod daemon;
+pub mod endpoints;
+pub mod identity;
+mod inflight;
+pub mod ipc;
+pub mod logging;
+pub mod models;
+mod queue;
+pub mod retry;
+mod signals;
+mod subscriptions;
+pub mod tls;
+mod utils;
+
+pub use crate::channel::{Overflow, DEFAULT_CHANNEL_CAPACITY};
+pub use crate::daemon::{Daemon, DaemonHandle, OpenDaemon, ReloadDaemon};
+pub use crate::inflight::DEFAULT_MAX_INFLIGHT;
+pub use crate::queue::{FsyncPolicy, DEFAULT_QUEUE_MAX_BYTES};
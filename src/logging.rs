@@ -0,0 +1,96 @@
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::{Serialize, Deserialize};
+
+pub const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::Info;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // One human readable line per record
+    Text,
+    // One JSON object per line, for log collectors
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format {:?}, expected text or json.", s)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: String,
+    level: Level,
+    target: &'a str,
+    message: String,
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+static LOGGER: Logger = Logger;
+
+// Writes every record to stderr, which ends up in the file
+// given to the daemon or on the terminal when running in the
+// foreground. Stdout is left to what the client subcommands
+// print.
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let timestamp = humantime::format_rfc3339_millis(SystemTime::now()).to_string();
+        let line = if JSON.load(Ordering::Relaxed) {
+            let json = serde_json::to_string(&JsonRecord {
+                timestamp,
+                level: record.level(),
+                target: record.target(),
+                message: record.args().to_string(),
+            });
+            match json {
+                Ok(j) => j,
+                Err(_) => return,
+            }
+        } else {
+            format!("{} {:<5} {}: {}", timestamp, record.level(), record.target(), record.args())
+        };
+
+        // There is nowhere left to report a failed write
+        let _ = writeln!(io::stderr(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+// Installs the logger, can only be called once.
+pub fn init(level: LevelFilter, format: LogFormat) -> Result<(), String> {
+    if let Err(e) = log::set_logger(&LOGGER) {
+        return Err(format!("Error installing logger: {}", e));
+    }
+    configure(level, format);
+    Ok(())
+}
+
+// Changes the level and format of the installed logger
+pub fn configure(level: LevelFilter, format: LogFormat) {
+    log::set_max_level(level);
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
@@ -1,156 +1,549 @@
-extern crate websocket;
 extern crate clap;
 extern crate daemonize;
-extern crate mac_address;
 
-use uuid::Uuid;
-use std::sync::mpsc::channel;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use clap::Clap;
 use daemonize::Daemonize;
-use std::thread::JoinHandle;
-use zmq;
-use mac_address::get_mac_address;
-use std::collections::HashSet;
-use std::sync::{Arc, Mutex};
-
-mod connection;
-mod models;
-mod ipc;
-mod utils;
-
-use crate::models::{Request, ClientInformation, InboundMessage};
-
-
-fn initialize<'a>(
-    account_id: &'a str,
-    api_key: &'a str,
-    device_type_id: &'a str,
-    device_id: &'a str,
-    outbound_port: &'a str,
-    inbound_port: &'a str,
-) -> (JoinHandle<()>, JoinHandle<()>, JoinHandle<()>) {
-/*
-        Steps:
-        - store account_id, api_key
-        - create device_id from hardware information (MAC address?)
-        - initialize MPSC channel
-        - initialize websocket handler
-    */
-    let context = zmq::Context::new();
-    // For messages that come into the websocket, this is a channel
-    // to comunicate with the process outside
-    let inbound_socket = context.socket(zmq::PUB).unwrap();
-    let inbound_tcp_port = format!("tcp://*:{}", inbound_port);
-    assert!(inbound_socket.bind(&inbound_tcp_port).is_ok());
-
-    // This is a PUSH socket such that the websocket thread
-    // can tell the thread handles incoming messages
-    // from the client to close
-    let ipc_socket = context.socket(zmq::PUSH).unwrap();
-    let ipc_socket_port = format!("tcp://localhost:{}", outbound_port);
-    assert!(ipc_socket.connect(&ipc_socket_port).is_ok());
-
-    // HashSet of registered topics, useful when the server restarts
-    let registered_topics = Arc::new(Mutex::new(HashSet::<String>::new()));
-
-    // DEFINITIONS
-    // outbound_: data and structures supporting data
-    // moving from inside the system to the external server
-    // inbound_: data and structures supporting data
-    // moving from data received from the servers meant
-    // for internal consumption
-    let (outbound_sender, outbound_receiver) = channel::<Request>();
-    let (inbound_sender, inbound_receiver) = channel::<InboundMessage>();
-
-    let (outbound_message_thread, inbound_message_thead) = crate::ipc::initialize(
-        outbound_sender.clone(),
-        inbound_receiver,
-        outbound_port,
-        context.clone(),
-        inbound_socket,
-        registered_topics.clone(),
-    );
-
-    let client_information = ClientInformation::new(
-        device_id,
-        device_type_id,
-        account_id,
-        api_key,
-    );
-    let websocket_handler = crate::connection::initialize(
-        client_information,
-        outbound_sender,
-        outbound_receiver,
-        inbound_sender,
-        registered_topics,
-    );
-
-    (websocket_handler, outbound_message_thread, inbound_message_thead)
-}
+use std::sync::Arc;
+use std::time::Duration;
+use log::{LevelFilter, error, info};
+use zeroize::Zeroizing;
+
+mod cli;
+
+use herd_daemon::{
+    Daemon,
+    FsyncPolicy,
+    Overflow,
+    DEFAULT_CHANNEL_CAPACITY,
+    DEFAULT_QUEUE_MAX_BYTES,
+    DEFAULT_MAX_INFLIGHT,
+};
+use herd_daemon::models::{ClientMessage, ControlRequest};
+use crate::cli::DaemonEndpoints;
+use herd_daemon::config::{
+    FileConfig,
+    DEFAULT_OUTBOUND_PORT,
+    DEFAULT_INBOUND_PORT,
+    DEFAULT_CONTROL_PORT,
+    DEFAULT_SHUTDOWN_TIMEOUT_MILLIS,
+    DEFAULT_STATE_DIR,
+    DEFAULT_STDOUT_FILE,
+    DEFAULT_STDERR_FILE,
+};
+use herd_daemon::tls::TlsOptions;
+
+use herd_daemon::endpoints::{FileMode, SocketPermissions};
+use herd_daemon::curve::CurveServer;
+use herd_daemon::ipc::InboundFraming;
+use herd_daemon::identity::IdentitySource;
+use herd_daemon::logging::{LogFormat, DEFAULT_LOG_LEVEL};
+use herd_daemon::retry::{
+    RetryPolicy,
+    RetryStrategy,
+    DEFAULT_RETRY_BASE_MILLIS,
+    DEFAULT_RETRY_MAX_MILLIS,
+    DEFAULT_MAX_RETRIES,
+};
 
-#[derive(Debug, Clap)]
+#[derive(Debug, Clone, Clap)]
 struct Opts {
-    #[clap(short = "a", long = "account_id")]
-    account_id: String,
-    #[clap(short = "k", long = "api_key")]
-    api_key: String,
-    #[clap(short = "d", long = "device_type_id")]
-    device_type_id: String,
-    #[clap(short = "o", long = "outbound_port", default_value = "5555")]
-    outbound_port: String,
-    #[clap(short = "i", long = "inbound_port", default_value = "5556")]
-    inbound_port: String,
+    #[clap(short = 'a', long = "account_id")]
+    account_id: Option<String>,
+    // Wiped from memory once the options are dropped, a copy is
+    // kept for reloading
+    #[clap(short = 'k', long = "api_key", parse(from_str = secret))]
+    api_key: Option<Zeroizing<String>>,
+    #[clap(long = "api_key_file")]
+    api_key_file: Option<String>,
+    #[clap(short = 'd', long = "device_type_id")]
+    device_type_id: Option<String>,
+    #[clap(long = "device_id")]
+    device_id: Option<String>,
+    #[clap(long = "identity")]
+    identity: Option<IdentitySource>,
+    #[clap(long = "interface")]
+    interface: Option<String>,
+    #[clap(short = 'o', long = "outbound_port")]
+    outbound_port: Option<u16>,
+    #[clap(short = 'i', long = "inbound_port")]
+    inbound_port: Option<u16>,
+    #[clap(long = "outbound_endpoint")]
+    outbound_endpoint: Option<String>,
+    #[clap(long = "inbound_endpoint")]
+    inbound_endpoint: Option<String>,
+    #[clap(long = "control_port")]
+    control_port: Option<u16>,
+    #[clap(long = "control_endpoint")]
+    control_endpoint: Option<String>,
+    #[clap(long = "inbound_framing")]
+    inbound_framing: Option<InboundFraming>,
+    #[clap(long = "socket_owner")]
+    socket_owner: Option<String>,
+    #[clap(long = "socket_group")]
+    socket_group: Option<String>,
+    #[clap(long = "socket_mode")]
+    socket_mode: Option<FileMode>,
+    #[clap(long = "curve_secret_key_file")]
+    curve_secret_key_file: Option<String>,
+    #[clap(long = "curve_allowed_keys_file")]
+    curve_allowed_keys_file: Option<String>,
+    #[clap(short = 's', long = "server_url")]
+    server_url: Option<String>,
+    #[clap(short = 'c', long = "config")]
+    config: Option<String>,
+    #[clap(long = "ca_file")]
+    ca_file: Option<String>,
+    #[clap(long = "no_system_roots")]
+    no_system_roots: bool,
+    #[clap(long = "client_cert")]
+    client_cert: Option<String>,
+    #[clap(long = "client_key")]
+    client_key: Option<String>,
+    #[clap(long = "retry_strategy")]
+    retry_strategy: Option<RetryStrategy>,
+    #[clap(long = "retry_base_millis")]
+    retry_base_millis: Option<u64>,
+    #[clap(long = "retry_max_millis")]
+    retry_max_millis: Option<u64>,
+    #[clap(long = "max_retries")]
+    max_retries: Option<u32>,
+    #[clap(long = "state_dir")]
+    state_dir: Option<String>,
+    #[clap(long = "queue_max_bytes")]
+    queue_max_bytes: Option<u64>,
+    #[clap(long = "queue_max_age_secs")]
+    queue_max_age_secs: Option<u64>,
+    #[clap(long = "queue_fsync")]
+    queue_fsync: Option<FsyncPolicy>,
+    #[clap(long = "max_inflight")]
+    max_inflight: Option<usize>,
+    #[clap(long = "channel_capacity")]
+    channel_capacity: Option<usize>,
+    #[clap(long = "channel_overflow")]
+    channel_overflow: Option<Overflow>,
+    #[clap(long = "ephemeral")]
+    ephemeral: bool,
+    #[clap(short = 'f', long = "foreground")]
+    foreground: bool,
+    #[clap(long = "stdout_file")]
+    stdout_file: Option<String>,
+    #[clap(long = "stderr_file")]
+    stderr_file: Option<String>,
+    #[clap(long = "pid_file")]
+    pid_file: Option<String>,
+    #[clap(long = "working_directory")]
+    working_directory: Option<String>,
+    #[clap(long = "user")]
+    user: Option<String>,
+    #[clap(long = "group")]
+    group: Option<String>,
+    #[clap(long = "log_level")]
+    log_level: Option<LevelFilter>,
+    #[clap(long = "log_format")]
+    log_format: Option<LogFormat>,
+    #[clap(long = "shutdown_timeout_millis")]
+    shutdown_timeout_millis: Option<u64>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Clap)]
+enum Command {
+    /// Runs the daemon, the default
+    Run,
+    /// Prints the device id and exits
+    Identity,
+    /// Writes a new CURVE secret key to a file and prints its public key
+    Keypair {
+        #[clap(long = "secret_key_file")]
+        secret_key_file: String,
+    },
+    /// Sends data through a running daemon
+    Send {
+        #[clap(long = "topic", required = true)]
+        topic: Vec<String>,
+        #[clap(long = "json")]
+        json: String,
+        #[clap(long = "qos")]
+        qos: Option<u8>,
+        #[clap(long = "id")]
+        id: Option<String>,
+    },
+    /// Prints the messages a running daemon publishes
+    Subscribe {
+        #[clap(long = "topic")]
+        topic: Vec<String>,
+    },
+    /// Registers a running daemon to topics
+    Register {
+        #[clap(long = "topic", required = true)]
+        topic: Vec<String>,
+        #[clap(long = "id")]
+        id: Option<String>,
+    },
+    /// Unregisters a running daemon from topics
+    Unregister {
+        #[clap(long = "topic", required = true)]
+        topic: Vec<String>,
+        #[clap(long = "id")]
+        id: Option<String>,
+    },
+    /// Prints the state of a running daemon
+    Status,
+    /// Prints the topics a running daemon is registered to
+    Topics,
+    /// Shuts a running daemon down
+    Close,
+}
+
+// Subcommands that talk to a running daemon instead of being
+// one, None for the others
+fn run_client(command: &Command, endpoints: &DaemonEndpoints) -> Option<Result<(), String>> {
+    let result = match command {
+        Command::Send { topic, json, qos, id } => crate::cli::send(endpoints, topic.clone(), json, *qos, id.clone()),
+        Command::Subscribe { topic } => crate::cli::subscribe(endpoints, topic),
+        Command::Register { topic, id } => crate::cli::push(endpoints, &ClientMessage::Register {
+            topics: topic.clone(),
+            id: id.clone(),
+        }),
+        Command::Unregister { topic, id } => crate::cli::push(endpoints, &ClientMessage::Unregister {
+            topics: topic.clone(),
+            id: id.clone(),
+        }),
+        Command::Status => crate::cli::control(endpoints, &ControlRequest::Status),
+        Command::Topics => crate::cli::control(endpoints, &ControlRequest::Topics),
+        Command::Close => crate::cli::push(endpoints, &ClientMessage::Close),
+        Command::Run | Command::Identity | Command::Keypair { .. } => return None,
+    };
+    Some(result)
+}
+
+// Where the daemon's sockets are, for the daemon itself and for
+// the subcommands talking to it
+fn daemon_endpoints(opts: &Opts, file_config: &FileConfig) -> DaemonEndpoints {
+    DaemonEndpoints {
+        outbound: herd_daemon::config::resolve_endpoint(
+            opts.outbound_endpoint.as_deref(),
+            opts.outbound_port,
+            file_config.outbound_endpoint.as_deref(),
+            file_config.outbound_port,
+            DEFAULT_OUTBOUND_PORT,
+        ),
+        inbound: herd_daemon::config::resolve_endpoint(
+            opts.inbound_endpoint.as_deref(),
+            opts.inbound_port,
+            file_config.inbound_endpoint.as_deref(),
+            file_config.inbound_port,
+            DEFAULT_INBOUND_PORT,
+        ),
+        control: herd_daemon::config::resolve_endpoint(
+            opts.control_endpoint.as_deref(),
+            opts.control_port,
+            file_config.control_endpoint.as_deref(),
+            file_config.control_port,
+            DEFAULT_CONTROL_PORT,
+        ),
+        inbound_framing: opts.inbound_framing
+            .or(file_config.inbound_framing)
+            .unwrap_or(InboundFraming::Single),
+        curve: opts.curve_secret_key_file.is_some() || file_config.curve_secret_key_file.is_some(),
+    }
+}
+
+// Parses secrets given on the command line
+fn secret(s: &str) -> Zeroizing<String> {
+    Zeroizing::new(s.to_owned())
+}
+
+// Settings without a default have to be given on the command
+// line or in the config file
+fn required<'a>(cli: &'a Option<String>, file: &'a Option<String>, key: &str) -> Result<&'a str, String> {
+    match cli.as_ref().or(file.as_ref()) {
+        Some(value) => Ok(value),
+        None => Err(format!("Missing {}, pass --{} or set {} in the config file.", key, key, key)),
+    }
+}
+
+fn load_file_config(opts: &Opts) -> Result<FileConfig, String> {
+    match &opts.config {
+        Some(path) => FileConfig::load(path),
+        None => Ok(FileConfig::default()),
+    }
+}
+
+// Resolves what the connection is made with and applies the
+// logging settings. Called again when the configuration is
+// reloaded.
+fn configure(opts: &Opts, file_config: &FileConfig) -> Result<Daemon, String> {
+    let account_id = required(&opts.account_id, &file_config.account_id, "account_id")?;
+    let api_key = herd_daemon::config::resolve_api_key(
+        opts.api_key.as_ref().map(|key| key.as_str()),
+        opts.api_key_file.as_deref(),
+        file_config,
+    )?;
+    let device_type_id = required(&opts.device_type_id, &file_config.device_type_id, "device_type_id")?;
+    let server_url = herd_daemon::config::resolve_server_url(opts.server_url.as_deref(), file_config)?;
+
+    let tls_options = TlsOptions {
+        ca_file: opts.ca_file.clone().or_else(|| file_config.ca_file.clone()),
+        system_roots: !opts.no_system_roots && file_config.system_roots.unwrap_or(true),
+        client_cert: opts.client_cert.clone().or_else(|| file_config.client_cert.clone()),
+        client_key: opts.client_key.clone().or_else(|| file_config.client_key.clone()),
+    };
+
+    let retry_policy = RetryPolicy::new(
+        opts.retry_strategy.or(file_config.retry_strategy).unwrap_or(RetryStrategy::Fixed),
+        opts.retry_base_millis.or(file_config.retry_base_millis).unwrap_or(DEFAULT_RETRY_BASE_MILLIS),
+        opts.retry_max_millis.or(file_config.retry_max_millis).unwrap_or(DEFAULT_RETRY_MAX_MILLIS),
+        opts.max_retries.or(file_config.max_retries).unwrap_or(DEFAULT_MAX_RETRIES),
+    )?;
+
+    let shutdown_timeout = opts.shutdown_timeout_millis
+        .or(file_config.shutdown_timeout_millis)
+        .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_MILLIS);
+
+    Ok(Daemon::new(account_id, api_key, device_type_id)
+        .server_url(server_url.as_str())
+        .tls(tls_options)
+        .retry_policy(retry_policy)
+        .shutdown_timeout(Duration::from_millis(shutdown_timeout))
+        .logging(
+            opts.log_level.or(file_config.log_level).unwrap_or(DEFAULT_LOG_LEVEL),
+            opts.log_format.or(file_config.log_format).unwrap_or(LogFormat::Text),
+        ))
+}
+
+// Output of the daemon is appended so earlier runs are kept
+fn open_log_file(path: &str) -> Result<File, String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Error opening log file {}: {}", path, e))
 }
 
 fn main() {
     // cargo run -- -a acct -k key -p 1234 -d dev_abc123
-    let opts = Opts::parse();
+    let mut opts = Opts::parse();
+
+    // Installed with the command line settings so problems with
+    // the config file can be logged
+    let log_format = opts.log_format.unwrap_or(LogFormat::Text);
+    if let Err(e) = herd_daemon::logging::init(opts.log_level.unwrap_or(DEFAULT_LOG_LEVEL), log_format) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 
-    let stdout = File::create("/tmp/herd-daemon.out").expect("Failed to create output file.");
-    let stderr = File::create("/tmp/herd-daemon.err").expect("Faile to create input file.");
+    if let Some(Command::Keypair { secret_key_file }) = &opts.command {
+        match herd_daemon::curve::generate_keypair(secret_key_file) {
+            Ok(public_key) => println!("{}", public_key),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            },
+        }
+        return;
+    }
+
+    // Read again on reload, after daemonizing has changed the
+    // working directory
+    if let Some(config) = &opts.config {
+        opts.config = match std::env::current_dir() {
+            Ok(dir) => Some(dir.join(config).to_string_lossy().into_owned()),
+            Err(e) => {
+                error!("Error reading current directory: {}", e);
+                std::process::exit(1);
+            },
+        };
+    }
 
-    let addr = match get_mac_address() {
-        Ok(Some(ma)) => ma.bytes(),
-        Ok(None) => {
-            println!("No MAC address found, can't compute unique id.");
-            return;
+    let file_config = match load_file_config(&opts) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
         },
+    };
+
+    let daemon_endpoints = daemon_endpoints(&opts, &file_config);
+    if let Some(result) = opts.command.as_ref().and_then(|c| run_client(c, &daemon_endpoints)) {
+        if let Err(e) = result {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Daemonizing changes the working directory, so relative
+    // paths are resolved now
+    let state_dir = opts.state_dir.clone()
+        .or_else(|| file_config.state_dir.clone())
+        .unwrap_or_else(|| DEFAULT_STATE_DIR.to_owned());
+    let state_dir = match std::env::current_dir() {
+        Ok(dir) => dir.join(state_dir),
         Err(e) => {
-            println!("Error obtaining mac address, can't compute unique id. {}", e);
-            return;
+            error!("Error reading current directory: {}", e);
+            std::process::exit(1);
         },
     };
 
-    let uuid = Uuid::new_v5(&Uuid::NAMESPACE_DNS, &addr);
-    let mut buffer: [u8; 45] = Uuid::encode_buffer();
-    let uuid = uuid.to_simple().encode_lower(&mut buffer);
-    let device_id = format!("dev_{}", uuid);
+    let device_id = match opts.device_id.clone().or_else(|| file_config.device_id.clone()) {
+        Some(device_id) => device_id,
+        None => {
+            let device_id = herd_daemon::identity::device_id(
+                opts.identity.or(file_config.identity).unwrap_or(IdentitySource::Mac),
+                opts.interface.as_deref().or(file_config.interface.as_deref()),
+                &state_dir,
+            );
+            match device_id {
+                Ok(d) => d,
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                },
+            }
+        },
+    };
 
-    let daemonize = Daemonize::new()
-        .stdout(stdout)
-        .stderr(stderr);
+    if let Some(Command::Identity) = opts.command {
+        println!("{}", device_id);
+        return;
+    }
 
-    match daemonize.start() {
-        Ok(_) => println!("Daemon started."),
+    let daemon = match configure(&opts, &file_config) {
+        Ok(d) => d,
         Err(e) => {
-            eprintln!("Error starting daemon: {}", e);
-            return;
+            error!("{}", e);
+            std::process::exit(1);
+        },
+    };
+
+    // The secret key may only be readable before privileges
+    // are dropped
+    let curve = match opts.curve_secret_key_file.as_deref().or(file_config.curve_secret_key_file.as_deref()) {
+        Some(secret_key_file) => CurveServer::load(
+            secret_key_file,
+            file_config.curve_allowed_keys.as_deref().unwrap_or(&[]),
+            opts.curve_allowed_keys_file.as_deref().or(file_config.curve_allowed_keys_file.as_deref()),
+        ).map(Some),
+        None if opts.curve_allowed_keys_file.is_some() => {
+            Err("Missing curve_secret_key_file, CURVE allowed keys were given without it.".to_owned())
         },
+        None => Ok(None),
+    };
+    let curve = match curve {
+        Ok(c) => c,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        },
+    };
+
+    // Only the settings of the connection and logging can be
+    // reloaded, everything else is read once at startup
+    let reload_opts = opts.clone();
+    let DaemonEndpoints { outbound, inbound, control, inbound_framing, .. } = daemon_endpoints;
+    let mut daemon = daemon
+        .device_id(&device_id)
+        .reload(Arc::new(move || configure(&reload_opts, &load_file_config(&reload_opts)?)))
+        .endpoints(&outbound, &inbound, &control)
+        .inbound_framing(inbound_framing)
+        // Applied to the socket files of ipc:// endpoints
+        .socket_permissions(SocketPermissions {
+            owner: opts.socket_owner.clone().or_else(|| file_config.socket_owner.clone()),
+            group: opts.socket_group.clone().or_else(|| file_config.socket_group.clone()),
+            mode: opts.socket_mode.or(file_config.socket_mode),
+        })
+        .state_dir(state_dir)
+        .queue_max_bytes(opts.queue_max_bytes.or(file_config.queue_max_bytes).unwrap_or(DEFAULT_QUEUE_MAX_BYTES))
+        .queue_max_age(match opts.queue_max_age_secs.or(file_config.queue_max_age_secs) {
+            Some(0) | None => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+        })
+        .queue_fsync(opts.queue_fsync.or(file_config.queue_fsync).unwrap_or(FsyncPolicy::Always))
+        .max_inflight(opts.max_inflight.or(file_config.max_inflight).unwrap_or(DEFAULT_MAX_INFLIGHT))
+        .channel_capacity(opts.channel_capacity.or(file_config.channel_capacity).unwrap_or(DEFAULT_CHANNEL_CAPACITY))
+        .channel_overflow(opts.channel_overflow.or(file_config.channel_overflow).unwrap_or(Overflow::Block))
+        // Ephemeral devices start without any registrations every time
+        .ephemeral(opts.ephemeral || !file_config.persist_topics.unwrap_or(true))
+        .handle_signals(true);
+    if let Some(curve) = curve {
+        daemon = daemon.curve(curve);
     }
 
-    let (websocket_handler, outbound_message_thread, inbound_message_thead) = initialize(
-        &opts.account_id,
-        &opts.api_key,
-        &opts.device_type_id,
-        &device_id,
-        &opts.outbound_port,
-        &opts.inbound_port,
-    );
-
-    println!("Waiting for join");
-    let _ = websocket_handler.join();
-    let _ = inbound_message_thead.join();
-    let _ = outbound_message_thread.join();
-    println!("Daemon exited");
+    // The queue and the registered topics are opened before
+    // privileges are dropped
+    let daemon = match daemon.open() {
+        Ok(d) => d,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        },
+    };
+
+    // In the foreground the process is left as is, for running
+    // under a supervisor such as systemd or in a container
+    let foreground = opts.foreground || file_config.foreground.unwrap_or(false);
+    if foreground {
+        info!("Running in the foreground.");
+    } else {
+        let stdout_file = opts.stdout_file
+            .or(file_config.stdout_file)
+            .unwrap_or_else(|| DEFAULT_STDOUT_FILE.to_owned());
+        let stderr_file = opts.stderr_file
+            .or(file_config.stderr_file)
+            .unwrap_or_else(|| DEFAULT_STDERR_FILE.to_owned());
+        let (stdout, stderr) = match (open_log_file(&stdout_file), open_log_file(&stderr_file)) {
+            (Ok(out), Ok(err)) => (out, err),
+            (Err(e), _) | (_, Err(e)) => {
+                error!("{}", e);
+                std::process::exit(1);
+            },
+        };
+
+        let mut daemonize = Daemonize::new()
+            .stdout(stdout)
+            .stderr(stderr);
+        if let Some(pid_file) = opts.pid_file.or(file_config.pid_file) {
+            // Written after the working directory changes
+            let pid_file = match std::env::current_dir() {
+                Ok(dir) => dir.join(pid_file),
+                Err(e) => {
+                    error!("Error reading current directory: {}", e);
+                    std::process::exit(1);
+                },
+            };
+            daemonize = daemonize.pid_file(pid_file).chown_pid_file(true);
+        }
+        if let Some(working_directory) = opts.working_directory.or(file_config.working_directory) {
+            daemonize = daemonize.working_directory(working_directory);
+        }
+        if let Some(user) = opts.user.or(file_config.user) {
+            daemonize = daemonize.user(user.as_str());
+        }
+        if let Some(group) = opts.group.or(file_config.group) {
+            daemonize = daemonize.group(group.as_str());
+        }
+
+        match daemonize.start() {
+            Ok(_) => info!("Daemon started."),
+            Err(e) => {
+                error!("Error starting daemon: {}", e);
+                std::process::exit(1);
+            },
+        }
+    }
+
+    let handle = match daemon.start() {
+        Ok(h) => h,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        },
+    };
+
+    info!("Waiting for threads to exit.");
+    handle.wait();
+    info!("Daemon exited.");
 }
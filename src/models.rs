@@ -1,5 +1,12 @@
+use std::convert::TryFrom;
 use serde::{Serialize, Deserialize};
 use serde_json::{Value};
+use url::Url;
+use native_tls::TlsConnector;
+use zeroize::Zeroizing;
+
+use crate::channel::Item;
+use crate::tls::TlsOptions;
 
 #[derive(Serialize)]
 pub struct Data {
@@ -15,31 +22,188 @@ pub struct OutboundMessage {
     pub data: Value,
 }
 
+// Delivery guarantee for an outbound message, sent as
+// 0 or 1 over the wire
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum Qos {
+    // Sent once, lost if the connection drops before the
+    // server receives it
+    #[default]
+    AtMostOnce,
+    // Sent again on every reconnect until the server
+    // acknowledges it
+    AtLeastOnce,
+}
+
+impl TryFrom<u8> for Qos {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Qos, String> {
+        match value {
+            0 => Ok(Qos::AtMostOnce),
+            1 => Ok(Qos::AtLeastOnce),
+            _ => Err(format!("Invalid qos {}, expected 0 or 1.", value)),
+        }
+    }
+}
+
+impl From<Qos> for u8 {
+    fn from(qos: Qos) -> u8 {
+        match qos {
+            Qos::AtMostOnce => 0,
+            Qos::AtLeastOnce => 1,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ClientMessage {
     Data {
         topics: Vec<String>,
         data: Value,
+        #[serde(default)]
+        qos: Qos,
+        // Chosen by the client, echoed back in Ack and Nack
+        id: Option<String>,
     },
     Register {
         topics: Vec<String>,
+        id: Option<String>,
+    },
+    Unregister {
+        topics: Vec<String>,
+        id: Option<String>,
+    },
+    // Replied to with InboundMessage::Registrations
+    ListRegistrations {
+        id: Option<String>,
+    },
+    // Reads the config file again, same as SIGHUP
+    Reload {
+        id: Option<String>,
     },
     Close,
     WebsocketClose,
 }
 
+// How far a message from a local client has made it
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum AckStatus {
+    // The message was valid
+    Parsed,
+    // The message was written to the outbound queue, or the
+    // topics of a register or unregister sent while reconnecting
+    // were saved to be registered once connected
+    Queued,
+    // The message was written to the websocket
+    Sent,
+    // The server acknowledged a qos 1 message
+    Delivered,
+    // The configuration was reloaded
+    Applied,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum InboundMessage {
     Data(String),
-    Restart,
+    Restart {
+        attempt: u32,
+        max_retries: Option<u32>,
+        next_delay_millis: u64,
+    },
+    Ack {
+        id: String,
+        status: AckStatus,
+    },
+    Nack {
+        id: String,
+        reason: String,
+    },
+    // Sent in reply to ListRegistrations, and without an id
+    // whenever the server confirms registrations
+    Registrations {
+        id: Option<String>,
+        confirmed: Vec<String>,
+        pending: Vec<String>,
+    },
     Close,
 }
 
-#[derive(Serialize, Debug, Clone)]
+impl Item for InboundMessage {
+    // Close is what tells the publisher to stop
+    fn droppable(&self) -> bool {
+        !matches!(self, InboundMessage::Close)
+    }
+}
+
+// Commands accepted on the control socket, each answered with
+// a ControlResponse
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ControlRequest {
+    Status,
+    // Replied to with the registered topics
+    Topics,
+    Reconnect,
+    // Send what is waiting in the outbound queue now
+    Flush,
+    // Same as a Close message
+    Shutdown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    // Waiting to make the next attempt
+    Reconnecting,
+    // Closed for good, the daemon is exiting
+    Closed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub state: ConnectionState,
+    pub device_id: String,
+    pub server_url: String,
+    // Attempts made since the connection was last up
+    pub attempt: u32,
+    pub max_retries: Option<u32>,
+    pub uptime_secs: u64,
+    pub connected_secs: Option<u64>,
+    // Events waiting to be sent
+    pub queue_depth: usize,
+    // Events sent but not acknowledged by the server
+    pub inflight: usize,
+    // Messages dropped because the outbound queue or a channel
+    // between local clients and the connection was full
+    pub outbound_dropped: u64,
+    pub inbound_dropped: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ControlResponse {
+    Status(Status),
+    Topics {
+        confirmed: Vec<String>,
+        pending: Vec<String>,
+    },
+    Ok,
+    Error {
+        reason: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Event {
     Message {
+        id: u64,
+        qos: Qos,
         seconds_since_unix: u64,
         nano_seconds: u32,
         topics: Vec<String>,
@@ -47,13 +211,47 @@ pub enum Event {
     },
     Register {
         topics: Vec<String>,
+    },
+    Unregister {
+        topics: Vec<String>,
     }
 }
 
+// Control frames sent by the Herd server, any other text
+// frame is data meant for local clients
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+pub enum ServerMessage {
+    Ack {
+        id: u64,
+    },
+    // Topics the server has registered the device to
+    Registered {
+        topics: Vec<String>,
+    },
+}
+
 pub enum Request {
-    Data(Event),
+    // The event and the client id to acknowledge once it's sent
+    Data(Event, Option<String>),
+    // Send whatever is waiting in the outbound queue
+    Flush,
+    // Reload the configuration, with the client id to
+    // acknowledge once it's applied
+    Reload(Option<String>),
+    // Drop the connection and connect again right away
+    Reconnect,
     Close,
-    Pong(Vec<u8>)
+}
+
+impl Item for Request {
+    fn droppable(&self) -> bool {
+        matches!(self, Request::Data(..))
+    }
+
+    fn signal(&self) -> bool {
+        matches!(self, Request::Flush)
+    }
 }
 
 #[derive(Clone)]
@@ -61,5 +259,11 @@ pub struct ClientInformation {
     pub device_id: String,
     pub device_type_id: String,
     pub account_id: String,
-    pub api_key: String,
+    // Wiped from memory once the last copy is dropped
+    pub api_key: Zeroizing<String>,
+    pub server_url: Url,
+    pub tls_connector: Option<TlsConnector>,
+    // What the connector was built from, to tell whether it
+    // changed when the configuration is reloaded
+    pub tls_options: TlsOptions,
 }
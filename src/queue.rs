@@ -0,0 +1,640 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use serde::{Serialize, Deserialize};
+use tokio::sync::Notify;
+use log::warn;
+
+use crate::channel::Overflow;
+use crate::inflight::Inflight;
+use crate::models::{Event, Qos};
+
+pub const DEFAULT_QUEUE_MAX_BYTES: u64 = 16 * 1024 * 1024;
+
+const LOG_FILE: &str = "outbound.queue";
+const HEAD_FILE: &str = "outbound.head";
+
+// Consumed bytes at the start of the log before it's rewritten
+// without them
+const COMPACT_BYTES: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum FsyncPolicy {
+    // Sync after every write, nothing acknowledged is ever lost
+    Always,
+    // Sync after every n appends
+    Every(u32),
+    // Leave flushing to the operating system
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FsyncPolicy, String> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => match s.parse::<u32>() {
+                Ok(n) if n > 0 => Ok(FsyncPolicy::Every(n)),
+                _ => Err(format!(
+                    "Unknown fsync policy {:?}, expected always, never or a number of writes.",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for FsyncPolicy {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<FsyncPolicy, String> {
+        s.parse()
+    }
+}
+
+// What happened to an entry pushed to the queue
+#[derive(Debug)]
+pub enum Pushed {
+    // Written to the log, after evicting these older entries
+    // to make room
+    Queued(Vec<QueueEntry>),
+    // Didn't fit, nothing was evicted
+    Full,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueEntry {
+    pub enqueued_at: u64,
+    pub event: Event,
+    // Id the local client gave the message, if any
+    #[serde(default)]
+    pub client_id: Option<String>,
+}
+
+impl QueueEntry {
+    pub fn new(event: Event, client_id: Option<String>) -> QueueEntry {
+        QueueEntry {
+            enqueued_at: now(),
+            event,
+            client_id,
+        }
+    }
+}
+
+// Append only queue of outbound events. Entries are written
+// as JSON lines to the log file and the byte offset of the
+// oldest entry not yet sent is kept in the head file. Once
+// every entry has been consumed the log is truncated, and once
+// enough of it has been it's rewritten without those entries.
+// At least once events leave the log once sent and wait in
+// the inflight set until the server acknowledges them.
+pub struct DiskQueue {
+    log_path: PathBuf,
+    head_path: PathBuf,
+    writer: File,
+    reader: BufReader<File>,
+    // Offset of the oldest unconsumed entry
+    head: u64,
+    // Offset of the end of the log
+    tail: u64,
+    // Length of the entry returned by the last peek
+    peeked: Option<u64>,
+    entries: usize,
+    max_bytes: u64,
+    max_age: Option<Duration>,
+    fsync: FsyncPolicy,
+    unsynced: u32,
+    // Largest message id written to the log
+    max_id: Option<u64>,
+    inflight: Inflight,
+    // Signaled whenever entries are consumed
+    room: Arc<Notify>,
+}
+
+impl DiskQueue {
+    pub fn open(
+        directory: &Path,
+        max_bytes: u64,
+        max_age: Option<Duration>,
+        fsync: FsyncPolicy,
+        max_inflight: usize,
+    ) -> Result<DiskQueue, String> {
+        if let Err(e) = fs::create_dir_all(directory) {
+            return Err(format!("Error creating queue directory {}: {}", directory.display(), e));
+        }
+
+        let log_path = directory.join(LOG_FILE);
+        let head_path = directory.join(HEAD_FILE);
+        let writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+            .map_err(|e| format!("Error opening queue {}: {}", log_path.display(), e))?;
+        let reader = File::open(&log_path)
+            .map_err(|e| format!("Error opening queue {}: {}", log_path.display(), e))?;
+
+        let inflight = Inflight::open(directory, max_inflight)?;
+
+        let head = match fs::read_to_string(&head_path) {
+            Ok(h) => h.trim().parse::<u64>().unwrap_or(0),
+            Err(_) => 0,
+        };
+
+        let mut queue = DiskQueue {
+            log_path,
+            head_path,
+            writer,
+            reader: BufReader::new(reader),
+            head,
+            tail: 0,
+            peeked: None,
+            entries: 0,
+            max_bytes,
+            max_age,
+            fsync,
+            unsynced: 0,
+            max_id: None,
+            inflight,
+            room: Arc::new(Notify::new()),
+        };
+        queue.recover()?;
+        Ok(queue)
+    }
+
+    // Counts the pending entries and drops a partially written
+    // last entry, which is left behind if the process died mid
+    // write.
+    fn recover(&mut self) -> Result<(), String> {
+        let length = self.writer.metadata().map_err(|e| self.error(e))?.len();
+        if self.head > length {
+            self.head = 0;
+        }
+
+        self.reader.seek(SeekFrom::Start(self.head)).map_err(|e| self.error(e))?;
+        let mut offset = self.head;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = self.reader.read_line(&mut line).map_err(|e| self.error(e))?;
+            if read == 0 || !line.ends_with('\n') {
+                break;
+            }
+            offset += read as u64;
+            self.entries += 1;
+            if let Ok(entry) = serde_json::from_str::<QueueEntry>(&line) {
+                self.max_id = self.max_id.max(message_id(&entry));
+            }
+        }
+
+        if offset < length {
+            warn!("Discarding {} bytes of partially written queue entry.", length - offset);
+            self.writer.set_len(offset).map_err(|e| self.error(e))?;
+        }
+        self.tail = offset;
+
+        if self.entries == 0 {
+            self.clear()?;
+        }
+        Ok(())
+    }
+
+    // Writes the entry to the log. When it doesn't fit, only
+    // Overflow::DropOldest makes room, by evicting the oldest
+    // entries not yet sent.
+    pub fn push(&mut self, entry: &QueueEntry, overflow: Overflow) -> Result<Pushed, String> {
+        let mut line = match serde_json::to_string(entry) {
+            Ok(l) => l,
+            Err(e) => return Err(format!("Error serializing queue entry: {}", e)),
+        };
+        line.push('\n');
+
+        let length = line.len() as u64;
+        if length > self.max_bytes {
+            return Err(format!("Message is larger than the outbound queue ({} bytes).", self.max_bytes));
+        }
+        let mut evicted = Vec::new();
+        while self.tail - self.head + length > self.max_bytes {
+            if overflow != Overflow::DropOldest {
+                return Ok(Pushed::Full);
+            }
+            match self.evict()? {
+                Some(e) => evicted.push(e),
+                None => break,
+            }
+        }
+
+        self.writer.write_all(line.as_bytes()).map_err(|e| self.error(e))?;
+        self.tail += line.len() as u64;
+        self.entries += 1;
+        self.max_id = self.max_id.max(message_id(entry));
+
+        self.unsynced += 1;
+        let sync = match self.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Every(n) => self.unsynced >= n,
+            FsyncPolicy::Never => false,
+        };
+        if sync {
+            self.writer.sync_data().map_err(|e| self.error(e))?;
+            self.unsynced = 0;
+        }
+        Ok(Pushed::Queued(evicted))
+    }
+
+    // Returns the oldest entry without removing it.
+    pub fn peek(&mut self) -> Result<Option<QueueEntry>, String> {
+        loop {
+            if self.head >= self.tail {
+                return Ok(None);
+            }
+
+            self.reader.seek(SeekFrom::Start(self.head)).map_err(|e| self.error(e))?;
+            let mut line = String::new();
+            let read = self.reader.read_line(&mut line).map_err(|e| self.error(e))?;
+
+            let entry: QueueEntry = match serde_json::from_str(&line) {
+                Ok(e) => e,
+                Err(e) => {
+                    warn!("Dropping unreadable queue entry: {}", e);
+                    self.advance(read as u64)?;
+                    continue;
+                }
+            };
+
+            self.peeked = Some(read as u64);
+            return Ok(Some(entry));
+        }
+    }
+
+    // Entries older than the maximum age should be dropped
+    // instead of sent.
+    pub fn is_expired(&self, entry: &QueueEntry) -> bool {
+        match self.max_age {
+            Some(max_age) => now().saturating_sub(entry.enqueued_at) > max_age.as_secs(),
+            None => false,
+        }
+    }
+
+    // Removes the entry returned by the last peek, unless it
+    // was evicted since.
+    pub fn pop(&mut self) -> Result<(), String> {
+        match self.peeked.take() {
+            Some(length) => self.advance(length),
+            None => Ok(()),
+        }
+    }
+
+    // Removes the oldest entry to make room and returns it, so
+    // whoever sent it can be told
+    fn evict(&mut self) -> Result<Option<QueueEntry>, String> {
+        let entry = self.peek()?;
+        self.pop()?;
+        Ok(entry)
+    }
+
+    // Moves the head past the oldest entry, which is length
+    // bytes long
+    fn advance(&mut self, length: u64) -> Result<(), String> {
+        self.head += length;
+        self.entries -= 1;
+        self.room.notify_one();
+        if self.entries == 0 {
+            return self.clear();
+        }
+        // Only once at least as much is consumed as is left, so
+        // the copying adds up to no more than what was written
+        if self.head >= COMPACT_BYTES && self.head >= self.tail - self.head {
+            match self.compact() {
+                Ok(()) => return Ok(()),
+                Err(e) => warn!("Error compacting queue, trying again later: {}", e),
+            }
+        }
+        self.write_head()
+    }
+
+    // Removes an entry returned by peek once it has been sent,
+    // holding on to it if the server has to acknowledge it.
+    pub fn sent(&mut self, entry: QueueEntry) -> Result<(), String> {
+        // Evicted while it was being sent, whoever sent it was
+        // already told it was dropped
+        if self.peeked.is_none() {
+            return Ok(());
+        }
+        let inserted = match entry.event {
+            Event::Message { id, qos: Qos::AtLeastOnce, .. } => self.inflight.insert(id, entry),
+            _ => Ok(()),
+        };
+        // Popped even if the inflight set couldn't be saved, the
+        // entry was sent and would otherwise be sent again right
+        // away
+        self.pop()?;
+        inserted
+    }
+
+    // Signaled whenever entries are consumed, for pushes that
+    // wait for room
+    pub fn room(&self) -> Arc<Notify> {
+        self.room.clone()
+    }
+
+    // Nothing more should be sent until the server catches up
+    // on acknowledgements.
+    pub fn inflight_full(&self) -> bool {
+        self.inflight.is_full()
+    }
+
+    // Returns the acknowledged entry, or None if the id wasn't
+    // waiting for an acknowledgement
+    pub fn acknowledge(&mut self, id: u64) -> Result<Option<QueueEntry>, String> {
+        self.inflight.remove(id)
+    }
+
+    pub fn unacknowledged(&self) -> Vec<QueueEntry> {
+        self.inflight.entries()
+    }
+
+    // Entries waiting to be sent
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    // Entries sent but not yet acknowledged
+    pub fn inflight_len(&self) -> usize {
+        self.inflight.len()
+    }
+
+    // Largest id of the messages waiting to be sent or
+    // acknowledged, new ones have to be larger
+    pub fn max_message_id(&self) -> Option<u64> {
+        self.max_id.max(self.inflight.max_id())
+    }
+
+    fn clear(&mut self) -> Result<(), String> {
+        self.writer.set_len(0).map_err(|e| self.error(e))?;
+        self.head = 0;
+        self.tail = 0;
+        self.entries = 0;
+        self.write_head()
+    }
+
+    // Copies the unconsumed entries to a new log that replaces
+    // the old one. The head is reset first, a crash before the
+    // rename sends consumed entries again rather than skipping
+    // unconsumed ones.
+    fn compact(&mut self) -> Result<(), String> {
+        // Not the head's temporary file, the head is written
+        // before this one is renamed
+        let temporary = self.log_path.with_extension("queue.tmp");
+        let copied = File::open(&self.log_path).and_then(|mut log| {
+            log.seek(SeekFrom::Start(self.head))?;
+            let mut file = File::create(&temporary)?;
+            io::copy(&mut log.take(self.tail - self.head), &mut file)?;
+            file.sync_data()
+        });
+        copied.map_err(|e| self.error(e))?;
+
+        let head = self.head;
+        self.head = 0;
+        let renamed = self.write_head()
+            .and_then(|_| fs::rename(&temporary, &self.log_path).map_err(|e| self.error(e)));
+        if let Err(e) = renamed {
+            self.head = head;
+            let _ = fs::remove_file(&temporary);
+            return Err(e);
+        }
+        self.tail -= head;
+
+        self.writer = OpenOptions::new()
+            .append(true)
+            .open(&self.log_path)
+            .map_err(|e| self.error(e))?;
+        let reader = File::open(&self.log_path).map_err(|e| self.error(e))?;
+        self.reader = BufReader::new(reader);
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    fn write_head(&self) -> Result<(), String> {
+        // Written to a temporary file and renamed so a crash
+        // never leaves a half written offset behind
+        let temporary = self.head_path.with_extension("tmp");
+        let result = File::create(&temporary).and_then(|mut f| {
+            write!(f, "{}", self.head)?;
+            if self.fsync != FsyncPolicy::Never {
+                f.sync_data()?;
+            }
+            fs::rename(&temporary, &self.head_path)
+        });
+        result.map_err(|e| format!("Error writing queue head {}: {}", self.head_path.display(), e))
+    }
+
+    fn error(&self, error: std::io::Error) -> String {
+        format!("Error accessing queue {}: {}", self.log_path.display(), error)
+    }
+}
+
+fn message_id(entry: &QueueEntry) -> Option<u64> {
+    match entry.event {
+        Event::Message { id, .. } => Some(id),
+        _ => None,
+    }
+}
+
+fn now() -> u64 {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(t) => t.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::inflight::DEFAULT_MAX_INFLIGHT;
+
+    fn directory(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("herd-queue-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn open(dir: &Path, max_bytes: u64) -> DiskQueue {
+        DiskQueue::open(dir, max_bytes, None, FsyncPolicy::Never, DEFAULT_MAX_INFLIGHT).unwrap()
+    }
+
+    fn entry(id: u64, data: serde_json::Value) -> QueueEntry {
+        QueueEntry::new(Event::Message {
+            id,
+            qos: Qos::AtMostOnce,
+            seconds_since_unix: 0,
+            nano_seconds: 0,
+            topics: vec!["a".to_owned()],
+            data,
+        }, Some(format!("m{}", id)))
+    }
+
+    fn id(entry: &QueueEntry) -> u64 {
+        message_id(entry).unwrap()
+    }
+
+    fn log_length(dir: &Path) -> u64 {
+        fs::metadata(dir.join(LOG_FILE)).unwrap().len()
+    }
+
+    #[test]
+    fn recovers_entries() {
+        let dir = directory("recover");
+        {
+            let mut queue = open(&dir, DEFAULT_QUEUE_MAX_BYTES);
+            for i in 1..=3 {
+                queue.push(&entry(i, json!(i)), Overflow::Block).unwrap();
+            }
+            queue.peek().unwrap();
+            queue.pop().unwrap();
+        }
+
+        let mut queue = open(&dir, DEFAULT_QUEUE_MAX_BYTES);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.max_message_id(), Some(3));
+        assert_eq!(queue.peek().unwrap().map(|e| id(&e)), Some(2));
+    }
+
+    #[test]
+    fn drops_a_partial_last_entry() {
+        let dir = directory("partial");
+        let length = {
+            let mut queue = open(&dir, DEFAULT_QUEUE_MAX_BYTES);
+            queue.push(&entry(1, json!(1)), Overflow::Block).unwrap();
+            log_length(&dir)
+        };
+        let mut log = OpenOptions::new().append(true).open(dir.join(LOG_FILE)).unwrap();
+        log.write_all(b"{\"enqueued_at\":").unwrap();
+
+        let mut queue = open(&dir, DEFAULT_QUEUE_MAX_BYTES);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(log_length(&dir), length);
+
+        queue.push(&entry(2, json!(2)), Overflow::Block).unwrap();
+        queue.peek().unwrap();
+        queue.pop().unwrap();
+        assert_eq!(queue.peek().unwrap().map(|e| id(&e)), Some(2));
+    }
+
+    #[test]
+    fn clears_once_empty() {
+        let dir = directory("clear");
+        let mut queue = open(&dir, DEFAULT_QUEUE_MAX_BYTES);
+        for i in 1..=2 {
+            queue.push(&entry(i, json!(i)), Overflow::Block).unwrap();
+        }
+        for i in 1..=2 {
+            assert_eq!(queue.peek().unwrap().map(|e| id(&e)), Some(i));
+            queue.pop().unwrap();
+        }
+        assert!(queue.peek().unwrap().is_none());
+        assert_eq!(queue.len(), 0);
+        assert_eq!(log_length(&dir), 0);
+        assert_eq!(fs::read_to_string(dir.join(HEAD_FILE)).unwrap(), "0");
+    }
+
+    #[test]
+    fn rejects_entries_over_the_limit() {
+        let dir = directory("limit");
+        let size = serde_json::to_string(&entry(1, json!(1))).unwrap().len() as u64 + 1;
+        let mut queue = open(&dir, size * 2);
+        queue.push(&entry(1, json!(1)), Overflow::Block).unwrap();
+        queue.push(&entry(2, json!(2)), Overflow::Block).unwrap();
+        for &overflow in &[Overflow::Block, Overflow::DropNewest] {
+            assert!(matches!(queue.push(&entry(3, json!(3)), overflow).unwrap(), Pushed::Full));
+        }
+        assert_eq!(queue.len(), 2);
+
+        // Consumed entries make room
+        queue.peek().unwrap();
+        queue.pop().unwrap();
+        assert!(matches!(queue.push(&entry(3, json!(3)), Overflow::Block).unwrap(), Pushed::Queued(_)));
+    }
+
+    #[test]
+    fn evicts_the_oldest_entries() {
+        let dir = directory("evict");
+        let size = serde_json::to_string(&entry(1, json!(1))).unwrap().len() as u64 + 1;
+        let mut queue = open(&dir, size * 2);
+        queue.push(&entry(1, json!(1)), Overflow::Block).unwrap();
+        queue.push(&entry(2, json!(2)), Overflow::Block).unwrap();
+
+        // Evicted while it's being sent
+        let mut sending = queue.peek().unwrap().unwrap();
+        if let Event::Message { qos, .. } = &mut sending.event {
+            *qos = Qos::AtLeastOnce;
+        }
+        match queue.push(&entry(3, json!(3)), Overflow::DropOldest).unwrap() {
+            Pushed::Queued(evicted) => assert_eq!(evicted.iter().map(id).collect::<Vec<_>>(), vec![1]),
+            Pushed::Full => panic!("Nothing was evicted."),
+        }
+        queue.sent(sending).unwrap();
+        assert_eq!(queue.inflight_len(), 0);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.peek().unwrap().map(|e| id(&e)), Some(2));
+
+        // Nothing makes room for an entry larger than the queue
+        let large = entry(4, json!("x".repeat(size as usize * 2)));
+        assert!(queue.push(&large, Overflow::DropOldest).is_err());
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    fn pops_sent_entries_the_inflight_set_cant_save() {
+        let dir = directory("unsaved");
+        let mut queue = open(&dir, DEFAULT_QUEUE_MAX_BYTES);
+        let mut sending = entry(1, json!(1));
+        if let Event::Message { qos, .. } = &mut sending.event {
+            *qos = Qos::AtLeastOnce;
+        }
+        queue.push(&sending, Overflow::Block).unwrap();
+        queue.push(&entry(2, json!(2)), Overflow::Block).unwrap();
+
+        // In the way of the inflight file being written
+        fs::create_dir(dir.join("inflight.tmp")).unwrap();
+        queue.peek().unwrap();
+        assert!(queue.sent(sending).is_err());
+        assert_eq!(queue.inflight_len(), 1);
+        assert_eq!(queue.peek().unwrap().map(|e| id(&e)), Some(2));
+    }
+
+    #[test]
+    fn compacts_consumed_entries() {
+        let dir = directory("compact");
+        let data = json!("x".repeat(100 * 1024));
+        let mut queue = open(&dir, DEFAULT_QUEUE_MAX_BYTES);
+        for i in 1..=20 {
+            queue.push(&entry(i, data.clone()), Overflow::Block).unwrap();
+        }
+        let size = log_length(&dir) / 20;
+        for _ in 1..=12 {
+            queue.peek().unwrap();
+            queue.pop().unwrap();
+        }
+        // Compacted after 11 of them
+        assert!(log_length(&dir) < size * 10);
+
+        queue.push(&entry(21, data), Overflow::Block).unwrap();
+        drop(queue);
+
+        let mut queue = open(&dir, DEFAULT_QUEUE_MAX_BYTES);
+        assert_eq!(queue.len(), 9);
+        for i in 13..=21 {
+            assert_eq!(queue.peek().unwrap().map(|e| id(&e)), Some(i));
+            queue.pop().unwrap();
+        }
+        assert_eq!(log_length(&dir), 0);
+    }
+}
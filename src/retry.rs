@@ -0,0 +1,188 @@
+use std::cmp;
+use std::str::FromStr;
+use std::time::Duration;
+use rand::Rng;
+use serde::Deserialize;
+
+pub const DEFAULT_RETRY_BASE_MILLIS: u64 = 5000;
+pub const DEFAULT_RETRY_MAX_MILLIS: u64 = 300_000;
+pub const DEFAULT_MAX_RETRIES: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RetryStrategy {
+    // Always wait the base delay
+    Fixed,
+    // Wait a random time between zero and base * 2^attempt,
+    // "full jitter"
+    Exponential,
+    // Wait a random time between the base and three times the
+    // previous delay, "decorrelated jitter"
+    Decorrelated,
+}
+
+impl FromStr for RetryStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RetryStrategy, String> {
+        match s {
+            "fixed" => Ok(RetryStrategy::Fixed),
+            "exponential" => Ok(RetryStrategy::Exponential),
+            "decorrelated" => Ok(RetryStrategy::Decorrelated),
+            _ => Err(format!(
+                "Unknown retry strategy {:?}, expected fixed, exponential or decorrelated.",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub strategy: RetryStrategy,
+    pub base_delay: Duration,
+    // Upper bound for any single delay
+    pub max_delay: Duration,
+    // None retries forever
+    pub max_retries: Option<u32>,
+}
+
+impl RetryPolicy {
+    pub fn new(
+        strategy: RetryStrategy,
+        base_delay_millis: u64,
+        max_delay_millis: u64,
+        max_retries: u32,
+    ) -> Result<RetryPolicy, String> {
+        if base_delay_millis == 0 {
+            return Err("The retry base delay must be greater than zero.".to_owned());
+        }
+        if max_delay_millis < base_delay_millis {
+            return Err("The maximum retry delay can't be less than the base delay.".to_owned());
+        }
+
+        Ok(RetryPolicy {
+            strategy,
+            base_delay: Duration::from_millis(base_delay_millis),
+            max_delay: Duration::from_millis(max_delay_millis),
+            max_retries: if max_retries == 0 { None } else { Some(max_retries) },
+        })
+    }
+}
+
+// Tracks the attempts made since the last successful connection.
+pub(crate) struct RetryState {
+    pub attempt: u32,
+    previous_delay: Duration,
+}
+
+impl RetryState {
+    pub fn new() -> RetryState {
+        RetryState {
+            attempt: 0,
+            previous_delay: Duration::from_millis(0),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = RetryState::new();
+    }
+
+    // Records another attempt and returns how long to wait
+    // before making it, or None if the policy gave up.
+    pub fn next_delay(&mut self, policy: &RetryPolicy) -> Option<Duration> {
+        if let Some(max) = policy.max_retries {
+            if self.attempt >= max {
+                return None;
+            }
+        }
+        self.attempt += 1;
+
+        let base = policy.base_delay.as_millis() as u64;
+        let cap = policy.max_delay.as_millis() as u64;
+        let mut rng = rand::thread_rng();
+        let delay = match policy.strategy {
+            RetryStrategy::Fixed => base,
+            RetryStrategy::Exponential => {
+                let exponent = cmp::min(self.attempt - 1, 32);
+                let ceiling = cmp::min(cap, base.saturating_mul(1u64 << exponent));
+                rng.gen_range(0, ceiling + 1)
+            },
+            RetryStrategy::Decorrelated => {
+                let previous = cmp::max(base, self.previous_delay.as_millis() as u64);
+                let ceiling = cmp::min(cap, previous.saturating_mul(3));
+                rng.gen_range(base, ceiling + 1)
+            },
+        };
+
+        self.previous_delay = Duration::from_millis(delay);
+        Some(self.previous_delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(delay: Option<Duration>) -> u64 {
+        delay.unwrap().as_millis() as u64
+    }
+
+    #[test]
+    fn rejects_invalid_delays() {
+        assert!(RetryPolicy::new(RetryStrategy::Fixed, 0, 1000, 0).is_err());
+        assert!(RetryPolicy::new(RetryStrategy::Fixed, 1000, 999, 0).is_err());
+
+        let policy = RetryPolicy::new(RetryStrategy::Fixed, 1000, 1000, 0).unwrap();
+        assert_eq!(policy.max_retries, None);
+    }
+
+    #[test]
+    fn fixed_always_waits_the_base_delay() {
+        let policy = RetryPolicy::new(RetryStrategy::Fixed, 100, 1000, 0).unwrap();
+        let mut state = RetryState::new();
+        for attempt in 1..=20 {
+            assert_eq!(millis(state.next_delay(&policy)), 100);
+            assert_eq!(state.attempt, attempt);
+        }
+    }
+
+    #[test]
+    fn exponential_doubles_up_to_the_max_delay() {
+        let policy = RetryPolicy::new(RetryStrategy::Exponential, 100, 1000, 0).unwrap();
+        let mut state = RetryState::new();
+        // Far enough for the doubling to overflow without the
+        // clamping
+        for attempt in 1..=80 {
+            let ceiling = cmp::min(1000, 100u64.saturating_mul(1 << cmp::min(attempt - 1, 32)));
+            assert!(millis(state.next_delay(&policy)) <= ceiling);
+        }
+    }
+
+    #[test]
+    fn decorrelated_stays_between_the_base_and_max_delay() {
+        let policy = RetryPolicy::new(RetryStrategy::Decorrelated, 100, 1000, 0).unwrap();
+        let mut state = RetryState::new();
+        let mut previous = 0;
+        for _ in 1..=80 {
+            let delay = millis(state.next_delay(&policy));
+            assert!((100..=1000).contains(&delay), "{}", delay);
+            assert!(delay <= cmp::max(100, previous) * 3);
+            previous = delay;
+        }
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let policy = RetryPolicy::new(RetryStrategy::Fixed, 100, 1000, 3).unwrap();
+        let mut state = RetryState::new();
+        for _ in 1..=3 {
+            assert!(state.next_delay(&policy).is_some());
+        }
+        assert_eq!(state.next_delay(&policy), None);
+        assert_eq!(state.attempt, 3);
+
+        state.reset();
+        assert_eq!(millis(state.next_delay(&policy)), 100);
+    }
+}
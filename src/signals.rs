@@ -0,0 +1,55 @@
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use log::{error, info, warn};
+use signal_hook::iterator::Signals;
+use signal_hook::{SIGHUP, SIGINT, SIGTERM};
+
+use crate::models::ClientMessage;
+
+// Shuts the daemon down on SIGTERM and SIGINT by sending Close
+// to the outbound socket, the same way a local client would, so
+// the queue is flushed and local clients are notified. A second
+// signal exits right away. SIGHUP reloads the configuration.
+pub fn initialize(ipc_socket: Arc<Mutex<zmq::Socket>>) -> Result<(), String> {
+    let signals = match Signals::new([SIGTERM, SIGINT, SIGHUP].iter()) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Error registering signal handlers: {}", e)),
+    };
+
+    thread::spawn(move || {
+        let mut received = false;
+        for signal in signals.forever() {
+            if signal == SIGHUP {
+                info!("Received SIGHUP, reloading configuration.");
+                if let Err(e) = request(&ipc_socket, &ClientMessage::Reload { id: None }) {
+                    error!("Error requesting reload: {}", e);
+                }
+                continue;
+            }
+
+            if received {
+                warn!("Received signal {} while shutting down, exiting now.", signal);
+                process::exit(1);
+            }
+            received = true;
+            info!("Received signal {}, shutting down.", signal);
+            if let Err(e) = request_close(&ipc_socket) {
+                error!("Error requesting close: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+// Stops the thread reading from local clients, which in turn
+// closes the websocket connection if it's still open. Fails
+// instead of blocking if that thread already stopped.
+pub fn request_close(ipc_socket: &Mutex<zmq::Socket>) -> zmq::Result<()> {
+    request(ipc_socket, &ClientMessage::Close)
+}
+
+fn request(ipc_socket: &Mutex<zmq::Socket>, message: &ClientMessage) -> zmq::Result<()> {
+    let message = serde_json::to_string(message).unwrap();
+    ipc_socket.lock().unwrap().send(message.as_bytes(), zmq::DONTWAIT)
+}
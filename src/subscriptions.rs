@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const TOPICS_FILE: &str = "topics.json";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubscriptionState {
+    // Sent to the server, or waiting to be, but not confirmed
+    Pending,
+    // The server confirmed the registration on the current
+    // connection
+    Confirmed,
+}
+
+// The authoritative set of topics this device is registered to.
+// Topics stay in the set across reconnects and are only removed
+// when a local client unregisters them. Unless the device is
+// ephemeral the set is saved in the state directory so it
+// survives restarts of the daemon.
+pub struct Subscriptions {
+    topics: HashMap<String, SubscriptionState>,
+    path: Option<PathBuf>,
+}
+
+impl Subscriptions {
+    pub fn new() -> Subscriptions {
+        Subscriptions {
+            topics: HashMap::new(),
+            path: None,
+        }
+    }
+
+    // Loads the topics saved in the state directory, they are
+    // registered again once the daemon connects
+    pub fn open(directory: &Path) -> Result<Subscriptions, String> {
+        let path = directory.join(TOPICS_FILE);
+        let topics: Vec<String> = match fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str(&contents) {
+                Ok(t) => t,
+                Err(e) => return Err(format!("Error parsing {}: {}", path.display(), e)),
+            },
+            Err(_) => Vec::new(),
+        };
+
+        let mut subscriptions = Subscriptions {
+            topics: HashMap::new(),
+            path: Some(path),
+        };
+        for topic in topics {
+            subscriptions.topics.insert(topic, SubscriptionState::Pending);
+        }
+        Ok(subscriptions)
+    }
+
+    pub fn register(&mut self, topics: &[String]) -> Result<(), String> {
+        for topic in topics {
+            self.topics.entry(topic.clone()).or_insert(SubscriptionState::Pending);
+        }
+        self.save()
+    }
+
+    pub fn unregister(&mut self, topics: &[String]) -> Result<(), String> {
+        for topic in topics {
+            self.topics.remove(topic);
+        }
+        self.save()
+    }
+
+    // Topics the server confirmed that were since unregistered
+    // are ignored
+    pub fn confirm(&mut self, topics: &[String]) {
+        for topic in topics {
+            if let Some(state) = self.topics.get_mut(topic) {
+                *state = SubscriptionState::Confirmed;
+            }
+        }
+    }
+
+    // A new connection starts without any registrations, so
+    // everything has to be confirmed again
+    pub fn reset(&mut self) {
+        for state in self.topics.values_mut() {
+            *state = SubscriptionState::Pending;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.topics.is_empty()
+    }
+
+    pub fn topics(&self) -> Vec<String> {
+        self.with_state(|_| true)
+    }
+
+    pub fn confirmed(&self) -> Vec<String> {
+        self.with_state(|s| s == SubscriptionState::Confirmed)
+    }
+
+    pub fn pending(&self) -> Vec<String> {
+        self.with_state(|s| s == SubscriptionState::Pending)
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let path = match &self.path {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+
+        let contents = match serde_json::to_vec(&self.topics()) {
+            Ok(c) => c,
+            Err(e) => return Err(format!("Error serializing topics: {}", e)),
+        };
+
+        let temporary = path.with_extension("tmp");
+        let result = File::create(&temporary).and_then(|mut file| {
+            file.write_all(&contents)?;
+            file.sync_data()?;
+            fs::rename(&temporary, path)
+        });
+        result.map_err(|e| format!("Error writing {}: {}", path.display(), e))
+    }
+
+    fn with_state<F: Fn(SubscriptionState) -> bool>(&self, filter: F) -> Vec<String> {
+        let mut topics: Vec<String> = self.topics
+            .iter()
+            .filter(|(_, state)| filter(**state))
+            .map(|(topic, _)| topic.clone())
+            .collect();
+        topics.sort();
+        topics
+    }
+}
+
+// Topics pending now that were already pending at the last
+// check, the server may never have answered them
+pub fn unanswered(pending: &[String], previously_pending: &[String]) -> Vec<String> {
+    pending.iter().filter(|t| previously_pending.contains(t)).cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topics(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn confirms_registered_topics() {
+        let mut subscriptions = Subscriptions::new();
+        subscriptions.register(&topics(&["a", "b"])).unwrap();
+        assert_eq!(subscriptions.pending(), topics(&["a", "b"]));
+
+        // Unregistered topics stay unregistered
+        subscriptions.unregister(&topics(&["b"])).unwrap();
+        subscriptions.confirm(&topics(&["a", "b"]));
+        assert_eq!(subscriptions.confirmed(), topics(&["a"]));
+        assert!(subscriptions.pending().is_empty());
+
+        // Registering a confirmed topic again keeps it confirmed
+        subscriptions.register(&topics(&["a", "c"])).unwrap();
+        assert_eq!(subscriptions.confirmed(), topics(&["a"]));
+        assert_eq!(subscriptions.pending(), topics(&["c"]));
+    }
+
+    #[test]
+    fn reset_makes_every_topic_pending() {
+        let mut subscriptions = Subscriptions::new();
+        subscriptions.register(&topics(&["a", "b"])).unwrap();
+        subscriptions.confirm(&topics(&["a"]));
+
+        subscriptions.reset();
+        assert!(subscriptions.confirmed().is_empty());
+        assert_eq!(subscriptions.pending(), topics(&["a", "b"]));
+        assert_eq!(subscriptions.topics(), topics(&["a", "b"]));
+    }
+
+    #[test]
+    fn saved_topics_are_pending_once_opened() {
+        let dir = std::env::temp_dir().join(format!("herd-subscriptions-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        {
+            let mut subscriptions = Subscriptions::open(&dir).unwrap();
+            subscriptions.register(&topics(&["a", "b"])).unwrap();
+            subscriptions.confirm(&topics(&["a"]));
+            subscriptions.unregister(&topics(&["b"])).unwrap();
+        }
+
+        let subscriptions = Subscriptions::open(&dir).unwrap();
+        assert_eq!(subscriptions.pending(), topics(&["a"]));
+    }
+
+    #[test]
+    fn only_topics_pending_at_both_checks_are_unanswered() {
+        let mut subscriptions = Subscriptions::new();
+        subscriptions.register(&topics(&["a", "b"])).unwrap();
+        let first = subscriptions.pending();
+        assert_eq!(unanswered(&first, &[]), Vec::<String>::new());
+
+        // b was confirmed and c only just registered
+        subscriptions.confirm(&topics(&["b"]));
+        subscriptions.register(&topics(&["c"])).unwrap();
+        let second = subscriptions.pending();
+        assert_eq!(unanswered(&second, &first), topics(&["a"]));
+
+        subscriptions.confirm(&topics(&["a", "c"]));
+        assert!(unanswered(&subscriptions.pending(), &second).is_empty());
+    }
+}
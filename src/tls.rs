@@ -0,0 +1,78 @@
+use std::fs;
+use native_tls::{Certificate, Identity, TlsConnector};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsOptions {
+    // PEM file with one or more CA certificates to trust
+    pub ca_file: Option<String>,
+    // Whether the certificates in the system store are trusted
+    pub system_roots: bool,
+    // PEM certificate and PKCS #8 key presented for mutual TLS
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+}
+
+impl TlsOptions {
+    pub fn is_default(&self) -> bool {
+        self.ca_file.is_none()
+            && self.system_roots
+            && self.client_cert.is_none()
+            && self.client_key.is_none()
+    }
+
+    pub fn build(&self) -> Result<TlsConnector, String> {
+        let mut builder = TlsConnector::builder();
+        builder.disable_built_in_roots(!self.system_roots);
+
+        if let Some(path) = &self.ca_file {
+            let pem = read_file(path, "CA bundle")?;
+            for cert in split_pem_certificates(&pem) {
+                match Certificate::from_pem(cert.as_bytes()) {
+                    Ok(c) => builder.add_root_certificate(c),
+                    Err(e) => return Err(format!("Invalid certificate in CA bundle {}: {}", path, e)),
+                };
+            }
+        } else if !self.system_roots {
+            return Err("A CA bundle is required when the system store is not trusted.".to_owned());
+        }
+
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert_path), Some(key_path)) => {
+                let cert = read_file(cert_path, "client certificate")?;
+                let key = read_file(key_path, "client key")?;
+                match Identity::from_pkcs8(cert.as_bytes(), key.as_bytes()) {
+                    Ok(identity) => builder.identity(identity),
+                    Err(e) => return Err(format!("Invalid client certificate or key: {}", e)),
+                };
+            },
+            (None, None) => (),
+            _ => return Err("Both a client certificate and a client key are required for mutual TLS.".to_owned()),
+        };
+
+        match builder.build() {
+            Ok(c) => Ok(c),
+            Err(e) => Err(format!("Error building TLS configuration: {}", e)),
+        }
+    }
+}
+
+fn read_file(path: &str, description: &str) -> Result<String, String> {
+    match fs::read_to_string(path) {
+        Ok(c) => Ok(c),
+        Err(e) => Err(format!("Error reading {} {}: {}", description, path, e)),
+    }
+}
+
+// Certificate::from_pem only reads the first certificate
+// of a file, so bundles are split up before being added.
+fn split_pem_certificates(pem: &str) -> Vec<String> {
+    const END: &str = "-----END CERTIFICATE-----";
+    let mut certificates = Vec::new();
+    let mut rest = pem;
+    while let Some(end) = rest.find(END) {
+        let (certificate, remaining) = rest.split_at(end + END.len());
+        certificates.push(certificate.trim().to_owned());
+        rest = remaining;
+    }
+    certificates
+}
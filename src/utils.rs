@@ -1,9 +1,51 @@
 use std::any::Any;
 use std::fmt::Display;
+use std::panic;
+use std::sync::{Arc, Mutex};
+use tokio::task;
+use log::error;
+
+use crate::channel::Sender;
+use crate::models::{AckStatus, InboundMessage};
 
 pub fn maybe_error<T: Any, U: Display>(result: Result<T, U>) {
     match result {
         Ok(_) => (),
-        Err(e) => eprintln!("{}", e),
+        Err(e) => error!("{}", e),
+    }
+}
+
+// Receipts are only published for messages the client gave an id
+pub fn acknowledge(inbound_sender: &Sender<InboundMessage>, id: &Option<String>, status: AckStatus) {
+    if let Some(id) = id {
+        maybe_error(inbound_sender.push(InboundMessage::Ack {
+            id: id.clone(),
+            status,
+        }));
+    }
+}
+
+pub fn reject(inbound_sender: &Sender<InboundMessage>, id: &Option<String>, reason: String) {
+    if let Some(id) = id {
+        maybe_error(inbound_sender.push(InboundMessage::Nack {
+            id: id.clone(),
+            reason,
+        }));
+    }
+}
+
+// Runs f on a thread for blocking work. The queue, the inflight
+// entries and the subscriptions write and sync files while
+// locked, which would stall every other task on the runtime.
+pub async fn blocking<T, U, F>(shared: &Arc<Mutex<T>>, f: F) -> U
+where
+    T: Send + 'static,
+    U: Send + 'static,
+    F: FnOnce(&mut T) -> U + Send + 'static,
+{
+    let shared = shared.clone();
+    match task::spawn_blocking(move || f(&mut shared.lock().unwrap())).await {
+        Ok(u) => u,
+        Err(e) => panic::resume_unwind(e.into_panic()),
     }
 }